@@ -0,0 +1,295 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::instrument::{Envelope, Modulation, Waveform};
+
+pub const USAGE: &str = "\
+Usage: compose <command> <input.sht> [options]
+
+Commands:
+    render    Compose the sheet and write it to a WAV file
+    play      Compose the sheet and play it on the default output device
+    check     Parse the sheet and report any problems
+
+Options:
+    -o, --output <file>       WAV file to write (render only, default: <input>.wav)
+    -r, --sample-rate <hz>    Sample rate of the composition (default: 96000)
+    -b, --bit-depth <bits>    WAV bit depth: 16, 24 or 32 (default: 16)
+    -g, --gain <gain>         Linear gain applied before quantization (default: 0.15)
+    -i, --instrument <name>   Instrument to compose with: sine, square, saw, triangle or pulse
+                              (default: sine)
+    -e, --envelope <a,d,s,r>  Attack, decay and release in seconds and sustain level of the
+                              instrument, with `,exp` for exponential stages
+        --pulse-width <width> Fraction of every cycle the pulse is high, between 0 and 1
+                              (pulse only, default: 0.25)
+        --pwm <depth,rate>    Sweep of the pulse width around its width and its speed in Hz,
+                              or `off` for a steady pulse (pulse only, default: 0.15,0.5)
+    -t, --tuning <hz>         Frequency of A4, overriding the sheet's `tuning` field
+    -h, --help                Print this message";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Render { input: PathBuf, output: PathBuf },
+    Play { input: PathBuf },
+    Check { input: PathBuf },
+}
+
+impl Command {
+    pub fn input(&self) -> &PathBuf {
+        match self {
+            Command::Render { input, .. } => input,
+            Command::Play { input } => input,
+            Command::Check { input } => input,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstrumentKind {
+    Sine,
+    Oscillator(Waveform),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub sample_rate: u32,
+    pub bit_depth: u16,
+    pub gain: f32,
+    pub instrument: InstrumentKind,
+    /// Overrides the envelope of the instrument.
+    pub envelope: Option<Envelope>,
+    pub tuning: Option<f32>,
+}
+
+/// Parses the process arguments, excluding the program name. Returns `None` when help was
+/// requested.
+pub fn parse_args<I>(args: I) -> Result<Option<Options>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    let mut command = None;
+    let mut input = None;
+    let mut output = None;
+    let mut sample_rate = 96000;
+    let mut bit_depth = 16;
+    let mut gain = 0.15;
+    let mut instrument = InstrumentKind::Sine;
+    let mut envelope = None;
+    let mut tuning = None;
+    let mut width = None;
+    let mut modulation = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => output = Some(PathBuf::from(flag_value(&arg, args.next())?)),
+            "-r" | "--sample-rate" => sample_rate = parse_flag(&arg, args.next())?,
+            "-b" | "--bit-depth" => bit_depth = parse_flag(&arg, args.next())?,
+            "-g" | "--gain" => gain = parse_flag(&arg, args.next())?,
+            "-t" | "--tuning" => tuning = Some(parse_flag::<f32>(&arg, args.next())?),
+            "-i" | "--instrument" => {
+                instrument = parse_instrument(&flag_value(&arg, args.next())?)?
+            }
+            "-e" | "--envelope" => envelope = Some(parse_flag(&arg, args.next())?),
+            "--pulse-width" => width = Some(parse_flag::<f32>(&arg, args.next())?),
+            "--pwm" => {
+                modulation = match flag_value(&arg, args.next())?.as_str() {
+                    "off" => Some(None),
+                    value => Some(Some(value.parse()?)),
+                }
+            }
+            flag if flag.starts_with('-') => bail!("Unknown option '{}'.", flag),
+            _ if command.is_none() => command = Some(arg),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => bail!("Unexpected argument '{}'.", arg),
+        }
+    }
+
+    let command = command.ok_or_else(|| anyhow!("No command given."))?;
+    let input = input.ok_or_else(|| anyhow!("No input sheet given."))?;
+    let command = match command.as_str() {
+        "render" => Command::Render {
+            output: output.unwrap_or_else(|| input.with_extension("wav")),
+            input,
+        },
+        "play" => Command::Play { input },
+        "check" => Command::Check { input },
+        other => bail!("Unknown command '{}'.", other),
+    };
+
+    if sample_rate == 0 {
+        bail!("Sample rate must be greater than zero.");
+    }
+    if !matches!(bit_depth, 16 | 24 | 32) {
+        bail!("Bit depth must be 16, 24 or 32, not {}.", bit_depth);
+    }
+    if matches!(tuning, Some(tuning) if tuning <= 0.0) {
+        bail!("Tuning must be greater than zero.");
+    }
+    let instrument = match instrument {
+        InstrumentKind::Oscillator(Waveform::Pulse {
+            width: default_width,
+            modulation: default_modulation,
+        }) => {
+            let width = width.unwrap_or(default_width);
+            if !(width > 0.0 && width < 1.0) {
+                bail!("Pulse width must be between 0 and 1, not {}.", width);
+            }
+            InstrumentKind::Oscillator(Waveform::Pulse {
+                width,
+                modulation: modulation.unwrap_or(default_modulation),
+            })
+        }
+        _ if width.is_some() || modulation.is_some() => {
+            bail!("Pulse width and PWM only apply to the pulse instrument.")
+        }
+        other => other,
+    };
+
+    Ok(Some(Options {
+        command,
+        sample_rate,
+        bit_depth,
+        gain,
+        instrument,
+        envelope,
+        tuning,
+    }))
+}
+
+fn flag_value(flag: &str, value: Option<String>) -> Result<String> {
+    value.ok_or_else(|| anyhow!("Option '{}' requires a value.", flag))
+}
+
+fn parse_flag<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T> {
+    let value = flag_value(flag, value)?;
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value '{}' for option '{}'.", value, flag))
+}
+
+fn parse_instrument(name: &str) -> Result<InstrumentKind> {
+    match name {
+        "sine" => Ok(InstrumentKind::Sine),
+        "square" => Ok(InstrumentKind::Oscillator(Waveform::Square)),
+        "saw" => Ok(InstrumentKind::Oscillator(Waveform::Sawtooth)),
+        "triangle" => Ok(InstrumentKind::Oscillator(Waveform::Triangle)),
+        "pulse" => Ok(InstrumentKind::Oscillator(Waveform::Pulse {
+            width: 0.25,
+            modulation: Some(Modulation {
+                depth: 0.15,
+                rate: 0.5,
+            }),
+        })),
+        other => bail!("Unknown instrument '{}'.", other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::cli::{parse_args, Command, InstrumentKind};
+    use crate::instrument::{Envelope, Modulation, Waveform};
+    use crate::sheet::Curve;
+
+    fn args(input: &str) -> Vec<String> {
+        input.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn render_defaults() {
+        let options = parse_args(args("render sheets/canon_in_d.sht"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            options.command,
+            Command::Render {
+                input: PathBuf::from("sheets/canon_in_d.sht"),
+                output: PathBuf::from("sheets/canon_in_d.wav"),
+            }
+        );
+        assert_eq!(options.sample_rate, 96000);
+        assert_eq!(options.bit_depth, 16);
+        assert_eq!(options.instrument, InstrumentKind::Sine);
+    }
+
+    #[test]
+    fn render_flags() {
+        let options = parse_args(args(
+            "render in.sht -o out.wav -r 44100 -b 24 --gain 0.5 --tuning 415",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            options.command,
+            Command::Render {
+                input: PathBuf::from("in.sht"),
+                output: PathBuf::from("out.wav"),
+            }
+        );
+        assert_eq!(options.sample_rate, 44100);
+        assert_eq!(options.bit_depth, 24);
+        assert_eq!(options.gain, 0.5);
+        assert_eq!(options.tuning, Some(415.0));
+
+        let options = parse_args(args("play in.sht -i saw -e 0.01,0.2,0.6,0.5,exp"))
+            .unwrap()
+            .unwrap();
+        let envelope = Envelope::new(0.01, 0.2, 0.6, 0.5).with_curve(Curve::Exponential);
+        assert_eq!(
+            options.instrument,
+            InstrumentKind::Oscillator(Waveform::Sawtooth)
+        );
+        assert_eq!(options.envelope, Some(envelope));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse_args(args("render")).is_err());
+        assert!(parse_args(args("sing in.sht")).is_err());
+        assert!(parse_args(args("render in.sht -b 12")).is_err());
+        assert!(parse_args(args("play in.sht --instrument kazoo")).is_err());
+        assert!(parse_args(args("check in.sht -r")).is_err());
+        assert!(parse_args(args("play in.sht --envelope 0.1,0.1")).is_err());
+        assert!(parse_args(args("play in.sht -i pulse --pulse-width 1")).is_err());
+        assert!(parse_args(args("play in.sht -i pulse --pulse-width 0")).is_err());
+        assert!(parse_args(args("play in.sht -i pulse --pwm 0.2")).is_err());
+        assert!(parse_args(args("play in.sht -i saw --pulse-width 0.5")).is_err());
+        assert!(parse_args(args("play in.sht --pwm off")).is_err());
+    }
+
+    #[test]
+    fn pulse() {
+        let instrument = |input| parse_args(args(input)).unwrap().unwrap().instrument;
+        let pulse =
+            |width, modulation| InstrumentKind::Oscillator(Waveform::Pulse { width, modulation });
+        assert_eq!(
+            instrument("play in.sht -i pulse"),
+            pulse(
+                0.25,
+                Some(Modulation {
+                    depth: 0.15,
+                    rate: 0.5,
+                })
+            )
+        );
+        assert_eq!(
+            instrument("play in.sht --pulse-width 0.1 --pwm 0.05,2 -i pulse"),
+            pulse(
+                0.1,
+                Some(Modulation {
+                    depth: 0.05,
+                    rate: 2.0,
+                })
+            )
+        );
+        assert_eq!(
+            instrument("play in.sht -i pulse --pwm off"),
+            pulse(0.25, None)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use ndarray::{s, ArcArray1, Array1};
+use nom::lib::std::collections::{HashMap, HashSet};
+use rayon::prelude::*;
+
+use crate::instrument::Instrument;
+use crate::sheet::{MarkKind, Note, Sheet, TempoMap, DEFAULT_TUNING};
+
+/// Schedules the notes of a sheet on a timeline and has an instrument play them.
+pub struct Composer<I: Instrument> {
+    instrument: I,
+    sample_rate: u32,
+    tuning: f32,
+    samples: HashMap<(Note, usize), ArcArray1<f32>>,
+}
+
+impl<I: Instrument> Composer<I> {
+    pub fn new(instrument: I, sample_rate: u32) -> Composer<I> {
+        Composer {
+            instrument,
+            sample_rate,
+            tuning: DEFAULT_TUNING,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn compose(&mut self, sheet: &Sheet) -> Array1<f32> {
+        if self.tuning != sheet.tuning {
+            self.tuning = sheet.tuning;
+            self.samples.clear();
+        }
+
+        let tempo = sheet.tempo_map();
+        let line_time = sheet.line_value.divisor() as f64;
+
+        // The tracks are mixed with the lines of the sheet, on the same tempo map. Only the
+        // lines of the sheet follow its hairpins, as tracks keep dynamics of their own.
+        let events = sheet
+            .events()
+            .into_iter()
+            .map(|event| (event, true))
+            .chain(
+                sheet
+                    .tracks
+                    .iter()
+                    .flat_map(|track| track.events(sheet.line_value))
+                    .map(|event| (event, false)),
+            )
+            .map(|(event, hairpins)| {
+                let start = event.line as f64 + event.offset as f64 / line_time;
+                let end = start + event.length as f64 / line_time;
+                let loc = tempo.sample(start, self.sample_rate);
+                let length = tempo.sample(end, self.sample_rate) - loc;
+                let sounding = (length as f32 * event.note.sounding()).round() as usize;
+                let gain = if hairpins {
+                    self.hairpin_gain(sheet, &tempo, loc)
+                } else {
+                    1.0
+                };
+                (loc, event.note, sounding, gain)
+            })
+            .collect::<Vec<_>>();
+        self.load_sample_cache(
+            events
+                .iter()
+                .map(|(_, note, length, _)| (*note, *length))
+                .collect(),
+        );
+
+        let samples = events
+            .into_iter()
+            .map(|(loc, note, length, gain)| (loc, self.sample(note, length), gain))
+            .collect::<Vec<_>>();
+        // Release tails ring on past the end of the last line.
+        let composition_length = samples
+            .iter()
+            .map(|(loc, sample, _)| loc + sample.len())
+            .fold(
+                tempo.sample(sheet.lines.len() as f64, self.sample_rate),
+                usize::max,
+            );
+        let mut timeline = Array1::<f32>::zeros(composition_length);
+
+        for (loc, sample, gain) in samples.into_iter() {
+            let mut view = timeline.slice_mut(s![loc..loc + sample.len()]);
+            view.scaled_add(gain, &sample);
+        }
+
+        timeline
+    }
+
+    /// The gain of a note starting at sample `loc`, following the crescendo or diminuendo it
+    /// starts under. The note keeps that gain for as long as it sounds, even past the dynamic
+    /// closing the hairpin.
+    fn hairpin_gain(&self, sheet: &Sheet, tempo: &TempoMap, loc: usize) -> f32 {
+        for mark in sheet.marks.iter() {
+            let MarkKind::Hairpin(hairpin) = mark.kind else {
+                continue;
+            };
+            let start = tempo.sample(mark.line as f64, self.sample_rate);
+            let end = tempo.sample((mark.line + hairpin.lines) as f64, self.sample_rate);
+            if (start..end).contains(&loc) {
+                return hairpin.gain((loc - start) as f32 / (end - start) as f32);
+            }
+        }
+        1.0
+    }
+
+    /// The instrument's rendering of `note` held for `length` samples.
+    pub fn sample(&mut self, note: Note, length: usize) -> ArcArray1<f32> {
+        match self.samples.get(&(note, length)) {
+            Some(sample) => sample.clone(),
+            None => {
+                let sample = self.render(note, length);
+                self.samples.insert((note, length), sample.clone());
+                sample
+            }
+        }
+    }
+
+    fn load_sample_cache(&mut self, notes: HashSet<(Note, usize)>) {
+        let samples = notes
+            .par_iter()
+            .filter(|key| !self.samples.contains_key(key))
+            .map(|(note, length)| ((*note, *length), self.render(*note, *length)))
+            .collect::<Vec<((Note, usize), ArcArray1<f32>)>>();
+
+        samples.into_iter().for_each(|(key, sample)| {
+            self.samples.insert(key, sample);
+        });
+    }
+
+    fn render(&self, note: Note, length: usize) -> ArcArray1<f32> {
+        self.instrument
+            .render(
+                &note,
+                note.frequency(self.tuning),
+                note.amplitude(),
+                length,
+                self.sample_rate,
+            )
+            .into_shared()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use ndarray::{s, Array1};
+
+    use crate::composer::Composer;
+    use crate::instrument::{Envelope, Instrument};
+    use crate::parse::sheet;
+    use crate::sheet::Note;
+    use crate::unroll::unroll;
+
+    /// Holds the velocity of each note for as long as it sounds.
+    struct Organ;
+
+    impl Instrument for Organ {
+        fn render(&self, _: &Note, _: f32, velocity: f32, duration: usize, _: u32) -> Array1<f32> {
+            Array1::from_elem(duration, velocity)
+        }
+    }
+
+    /// Holds the velocity of each note under an envelope, which peaks with its accents.
+    struct Pad(Envelope);
+
+    impl Instrument for Pad {
+        fn render(
+            &self,
+            note: &Note,
+            _: f32,
+            velocity: f32,
+            duration: usize,
+            rate: u32,
+        ) -> Array1<f32> {
+            self.0.levels(note, duration, rate) * velocity
+        }
+    }
+
+    #[test]
+    fn schedules_and_mixes_tracks() {
+        let input = "60xq\n--\n@p\nC4q\n\nC4e C4h\n[track bass]\nR*2 C3qv127";
+        let sheet = sheet(Path::new("test.sht"), input).unwrap();
+        let actual = Composer::new(Organ, 16).compose(&sheet);
+        let p = 49.0 / 127.0;
+        let chord = [2.0 * p + 1.0, 2.0 * p + 1.0, p + 1.0, p + 1.0];
+        let expected = [[p; 4], [0.0; 4], chord, [p; 4]].concat();
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn hairpin_cut_short() {
+        let input = "60xq\n--\n@p\n@cresc\nC4q\nD4q\n@fine\nE4q\n@f\nF4q\n@dc al fine";
+        let sheet = unroll(&sheet(Path::new("test.sht"), input).unwrap());
+        let actual = Composer::new(Organ, 16).compose(&sheet);
+        assert_eq!(actual.len(), 24);
+        // A third of the way through the replayed crescendo when the piece ends at the fine.
+        let p = 49.0 / 127.0;
+        let third = p * (1.0 + (96.0 / 49.0 - 1.0) / 3.0);
+        assert!((actual[16] - p).abs() < 1e-6);
+        assert!((actual[23] - third).abs() < 1e-6);
+    }
+
+    #[test]
+    fn holds_across_hairpin_end() {
+        let input = "60xq\n--\n@p\n@cresc\nC4q\nD4h\n\n@f\nE4q\n[track bass]\nC3hv127";
+        let sheet = sheet(Path::new("test.sht"), input).unwrap();
+        let actual = Composer::new(Organ, 16).compose(&sheet);
+        // The half note keeps the gain it started with a third of the way through the
+        // crescendo, and the track is left at its own velocity.
+        let (p, f) = (49.0 / 127.0, 96.0 / 127.0);
+        let held = p * (1.0 + (96.0 / 49.0 - 1.0) / 3.0);
+        let expected = [[p + 1.0; 4], [held + 1.0; 4], [held; 4], [f; 4]].concat();
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn articulations() {
+        let input = "60xq\n--\nC4h'\n\nD4h_\n\nE4h>\n\nF4h";
+        let held = sheet(Path::new("test.sht"), input).unwrap();
+        let actual = Composer::new(Organ, 16).compose(&held);
+        // Staccato sounds for half its length, and legato rings into the next note.
+        let expected = [
+            [1.0; 4],
+            [0.0; 4],
+            [1.0; 4],
+            [1.0; 4],
+            [2.0, 2.0, 1.0, 1.0],
+            [1.0; 4],
+            [1.0; 4],
+            [1.0; 4],
+        ]
+        .concat();
+        assert_eq!(actual.to_vec(), expected);
+
+        // Accents peak at the end of the attack, a sixteenth of a second in.
+        let input = "60xq\n--\nC4h\n\nD4h>\n\nE4h^\n";
+        let accented = sheet(Path::new("test.sht"), input).unwrap();
+        let envelope = Envelope::new(1.0 / 16.0, 1.0 / 16.0, 0.5, 0.0);
+        let actual = Composer::new(Pad(envelope), 16).compose(&accented);
+        assert_eq!(actual.len(), 24);
+        assert!((actual[1] - 1.0).abs() < 1e-6);
+        assert!((actual[9] - 1.5).abs() < 1e-6);
+        assert!((actual[17] - 1.8).abs() < 1e-6);
+        // Marcato sounds for three quarters of its length.
+        assert!(actual.slice(s![17..22]).iter().all(|level| *level > 0.0));
+        assert!(actual.slice(s![22..]).iter().all(|level| *level == 0.0));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A problem found while parsing a sheet, located at a line and column of the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+    /// The offending text, empty when the problem is a missing token at the end of a line.
+    pub token: String,
+    /// A description of what would have been accepted at this position.
+    pub expected: String,
+    /// The full source line, used to render the snippet.
+    pub source: String,
+    /// 1-based number of the measure the problem is in, when the sheet has a time signature.
+    pub measure: Option<usize>,
+    /// The file the line was read from, when it is known.
+    pub file: Option<Rc<PathBuf>>,
+}
+
+impl ParseError {
+    pub fn new(
+        line: usize,
+        column: usize,
+        token: impl Into<String>,
+        expected: impl Into<String>,
+        source: impl Into<String>,
+    ) -> ParseError {
+        ParseError {
+            line,
+            column,
+            token: token.into(),
+            expected: expected.into(),
+            source: source.into(),
+            measure: None,
+            file: None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.token.as_str() {
+            "" => writeln!(
+                f,
+                "error: unexpected end of line, expected {}",
+                self.expected
+            )?,
+            token => writeln!(
+                f,
+                "error: unexpected `{}`, expected {}",
+                token, self.expected
+            )?,
+        }
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        match &self.file {
+            Some(file) => writeln!(
+                f,
+                "{} --> {}:{}:{}",
+                gutter,
+                file.display(),
+                self.line,
+                self.column
+            )?,
+            None => writeln!(f, "{} --> {}:{}", gutter, self.line, self.column)?,
+        }
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(self.column - 1),
+            "^".repeat(self.token.chars().count().max(1))
+        )?;
+        if let Some(measure) = self.measure {
+            write!(f, "\n{} = note: in measure {}", gutter, measure)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use ndarray::Array1;
+
+use crate::sheet::{Articulation, Curve, Note};
+
+/// An attack, decay, sustain and release envelope with its times in seconds, so that notes of
+/// every length start and end alike. The release starts when the note ends and rings past it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    /// Level held from the end of the decay until the note ends, from 0 to 1.
+    pub sustain: f32,
+    pub release: f32,
+    pub curve: Curve,
+}
+
+impl Envelope {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
+        Envelope {
+            attack,
+            decay,
+            sustain,
+            release,
+            curve: Curve::Linear,
+        }
+    }
+
+    pub fn with_curve(self, curve: Curve) -> Envelope {
+        Envelope { curve, ..self }
+    }
+
+    /// Number of samples sounded by a note held for `held` samples, including the release.
+    pub fn length(&self, held: usize, sample_rate: u32) -> usize {
+        held + (self.release * sample_rate as f32).round() as usize
+    }
+
+    /// Level of every sample of `note` held for `duration` samples, release included. Accents
+    /// peak above full level at the end of the attack, and tenuto notes hold their peak instead
+    /// of decaying.
+    pub fn levels(&self, note: &Note, duration: usize, sample_rate: u32) -> Array1<f32> {
+        let envelope = match note.articulation {
+            Some(Articulation::Tenuto) => Envelope {
+                sustain: 1.0,
+                ..*self
+            },
+            _ => *self,
+        };
+        let held = duration as f32 / sample_rate as f32;
+        let peak = note.articulation.map_or(1.0, Articulation::attack);
+        Array1::from_shape_fn(envelope.length(duration, sample_rate), |i| {
+            envelope.level(i as f32 / sample_rate as f32, held, peak)
+        })
+    }
+
+    /// Level `time` seconds into a note held for `held` seconds, peaking at `peak` at the end
+    /// of the attack. A note that ends before reaching its sustain releases from where it is.
+    pub fn level(&self, time: f32, held: f32, peak: f32) -> f32 {
+        if time >= held {
+            let from = self.level(held, f32::INFINITY, peak);
+            return self.segment(from, 0.0, (time - held) / self.release);
+        }
+        if time < self.attack {
+            self.segment(0.0, peak, time / self.attack)
+        } else if time < self.attack + self.decay {
+            self.segment(peak, self.sustain, (time - self.attack) / self.decay)
+        } else {
+            self.sustain
+        }
+    }
+
+    /// The level `fraction` of the way through a stage going from `from` to `to`. Exponential
+    /// stages move quickly at first and settle into their target, as analog envelopes do.
+    fn segment(&self, from: f32, to: f32, fraction: f32) -> f32 {
+        // A stage lasting no time at all is over as soon as it starts.
+        let fraction = if fraction.is_nan() {
+            1.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+        let shape = match self.curve {
+            Curve::Linear => fraction,
+            Curve::Exponential => (1.0 - (-5.0 * fraction).exp()) / (1.0 - (-5.0f32).exp()),
+        };
+        from + (to - from) * shape
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope::new(0.02, 0.1, 0.8, 0.15)
+    }
+}
+
+/// Reads an envelope written as `attack,decay,sustain,release`, optionally followed by `,exp`
+/// for exponential stages.
+impl FromStr for Envelope {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Envelope> {
+        let mut fields = input.split(',').map(str::trim);
+        let mut number = |name| -> anyhow::Result<f32> {
+            let field = fields
+                .next()
+                .ok_or_else(|| anyhow!("Envelope is missing its {}.", name))?;
+            field
+                .parse::<f32>()
+                .ok()
+                .filter(|value| *value >= 0.0)
+                .ok_or_else(|| anyhow!("Invalid envelope {} '{}'.", name, field))
+        };
+        let envelope = Envelope::new(
+            number("attack")?,
+            number("decay")?,
+            number("sustain")?,
+            number("release")?,
+        );
+        if envelope.sustain > 1.0 {
+            bail!("Envelope sustain must be between 0 and 1.");
+        }
+        match fields.next() {
+            None => Ok(envelope),
+            Some("exp") => Ok(envelope.with_curve(Curve::Exponential)),
+            Some(other) => bail!("Unknown envelope curve '{}'.", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::instrument::Envelope;
+    use crate::sheet::Curve;
+
+    #[test]
+    fn stages() {
+        let envelope = Envelope::new(0.1, 0.2, 0.5, 0.4);
+        assert_eq!(envelope.level(0.0, 1.0, 1.0), 0.0);
+        assert!((envelope.level(0.05, 1.0, 1.0) - 0.5).abs() < 1e-6);
+        assert!((envelope.level(0.2, 1.0, 1.0) - 0.75).abs() < 1e-6);
+        assert_eq!(envelope.level(0.6, 1.0, 1.0), 0.5);
+        assert!((envelope.level(1.2, 1.0, 1.0) - 0.25).abs() < 1e-6);
+        assert!(envelope.level(1.4, 1.0, 1.0).abs() < 1e-6);
+        // Released halfway through the attack, from the level reached.
+        assert!((envelope.level(0.25, 0.05, 1.0) - 0.25).abs() < 1e-6);
+        assert_eq!(envelope.length(1000, 1000), 1400);
+    }
+
+    #[test]
+    fn exponential_stages() {
+        let envelope = Envelope::new(0.1, 0.0, 1.0, 0.1).with_curve(Curve::Exponential);
+        let halfway = envelope.level(0.05, 1.0, 1.0);
+        assert!(halfway > 0.5 && halfway < 1.0);
+        assert!((envelope.level(0.1, 1.0, 1.0) - 1.0).abs() < 1e-6);
+        assert!(envelope.level(1.1, 1.0, 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn from_str() {
+        let envelope = "0.01, 0.1, 0.7, 0.3".parse::<Envelope>().unwrap();
+        assert_eq!(envelope, Envelope::new(0.01, 0.1, 0.7, 0.3));
+        let envelope = "0,0,1,0.5,exp".parse::<Envelope>().unwrap();
+        assert_eq!(envelope.curve, Curve::Exponential);
+        assert!("0.1,0.1,0.5".parse::<Envelope>().is_err());
+        assert!("0.1,0.1,1.5,0.1".parse::<Envelope>().is_err());
+        assert!("0.1,-1,0.5,0.1".parse::<Envelope>().is_err());
+        assert!("0.1,0.1,0.5,0.1,log".parse::<Envelope>().is_err());
+    }
+}
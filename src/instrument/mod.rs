@@ -1,3 +1,27 @@
+use ndarray::Array1;
+
+pub use envelope::*;
+pub use oscillator::*;
 pub use sine_generator::*;
 
+use crate::sheet::Note;
+
+mod envelope;
+mod oscillator;
 mod sine_generator;
+
+/// A sound source the composer plays notes on. Instruments render notes one at a time and may
+/// be asked to render several at once, from different threads.
+pub trait Instrument: Sync {
+    /// Renders `note` sounding at `frequency` Hz, held for `duration` samples at `sample_rate`
+    /// and played at `velocity`, from 0 to 1. The buffer may run on past `duration` for the
+    /// release of the note.
+    fn render(
+        &self,
+        note: &Note,
+        frequency: f32,
+        velocity: f32,
+        duration: usize,
+        sample_rate: u32,
+    ) -> Array1<f32>;
+}
@@ -0,0 +1,260 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use ndarray::{Array1, Zip};
+
+use crate::instrument::{Envelope, Instrument};
+use crate::sheet::Note;
+
+/// The shape of the wave an [`Oscillator`] plays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sawtooth,
+    Triangle,
+    /// A square wave high for `width` of every cycle, swept by `modulation` when set.
+    Pulse {
+        width: f32,
+        modulation: Option<Modulation>,
+    },
+}
+
+/// A low frequency sine sweeping the pulse width up and down by `depth` around its width.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modulation {
+    pub depth: f32,
+    /// Speed of the sweep in Hz.
+    pub rate: f32,
+}
+
+/// Reads a modulation written as `depth,rate`, with the rate in Hz.
+impl FromStr for Modulation {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Modulation> {
+        let fields = input.split(',').map(str::trim).collect::<Vec<_>>();
+        let [depth, rate] = fields[..] else {
+            bail!("Pulse width modulation must be written as depth,rate.");
+        };
+        let number = |name, field: &str| -> anyhow::Result<f32> {
+            field
+                .parse::<f32>()
+                .ok()
+                .filter(|value| value.is_finite() && *value >= 0.0)
+                .ok_or_else(|| anyhow!("Invalid modulation {} '{}'.", name, field))
+        };
+        let modulation = Modulation {
+            depth: number("depth", depth)?,
+            rate: number("rate", rate)?,
+        };
+        if modulation.depth > 0.5 {
+            bail!("Modulation depth must be between 0 and 0.5.");
+        }
+        Ok(modulation)
+    }
+}
+
+/// A classic subtractive synth oscillator shaped by an envelope. The corners of every wave are
+/// smoothed with PolyBLEP and PolyBLAMP residuals, so that the harmonics of high notes that
+/// would fold back below the Nyquist frequency are all but gone.
+pub struct Oscillator {
+    waveform: Waveform,
+    envelope: Envelope,
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform) -> Oscillator {
+        Oscillator {
+            waveform,
+            envelope: Envelope::default(),
+        }
+    }
+
+    pub fn with_envelope(self, envelope: Envelope) -> Oscillator {
+        Oscillator { envelope, ..self }
+    }
+
+    /// The wave at `phase` through its cycle, `time` seconds into the note, for a phase
+    /// advancing by `step` every sample.
+    fn wave(&self, phase: f32, step: f32, time: f32) -> f32 {
+        match self.waveform {
+            Waveform::Sawtooth => 2.0 * phase - 1.0 - poly_blep(phase, step),
+            Waveform::Square => pulse(phase, step, 0.5),
+            Waveform::Triangle => {
+                let naive = 1.0 - 4.0 * (phase - 0.5).abs();
+                let corners = poly_blamp(phase, step) - poly_blamp((phase + 0.5) % 1.0, step);
+                naive + 8.0 * step * corners
+            }
+            Waveform::Pulse { width, modulation } => {
+                let sweep = modulation.map_or(0.0, |modulation| {
+                    let angle = 2.0 * std::f32::consts::PI * modulation.rate * time;
+                    modulation.depth * angle.sin()
+                });
+                // A pulse too narrow for a couple of samples would vanish, or alias.
+                let limit = (2.0 * step).min(0.5);
+                pulse(phase, step, (width + sweep).clamp(limit, 1.0 - limit))
+            }
+        }
+    }
+}
+
+impl Instrument for Oscillator {
+    fn render(
+        &self,
+        note: &Note,
+        frequency: f32,
+        velocity: f32,
+        duration: usize,
+        sample_rate: u32,
+    ) -> Array1<f32> {
+        let step = frequency / sample_rate as f32;
+        let mut levels = self.envelope.levels(note, duration, sample_rate);
+        Zip::indexed(&mut levels).for_each(|i, level| {
+            let phase = (i as f64 * step as f64).fract() as f32;
+            let time = i as f32 / sample_rate as f32;
+            *level *= velocity * self.wave(phase, step, time);
+        });
+        levels
+    }
+}
+
+/// A pulse high for `width` of the cycle, centered around zero.
+fn pulse(phase: f32, step: f32, width: f32) -> f32 {
+    let naive = if phase < width { 1.0 } else { -1.0 };
+    let edges = poly_blep(phase, step) - poly_blep((phase + 1.0 - width) % 1.0, step);
+    naive + edges - (2.0 * width - 1.0)
+}
+
+/// The difference between a rising step from -1 to 1 at phase zero and its band-limited
+/// version, over the samples either side of it.
+fn poly_blep(phase: f32, step: f32) -> f32 {
+    if phase < step {
+        let x = phase / step;
+        -(1.0 - x) * (1.0 - x)
+    } else if phase > 1.0 - step {
+        let x = (phase - 1.0) / step;
+        (x + 1.0) * (x + 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// The difference between a corner whose slope rises by one per sample at phase zero and its
+/// band-limited version: the integral of [`poly_blep`] for a unit step.
+fn poly_blamp(phase: f32, step: f32) -> f32 {
+    let x = if phase < step {
+        1.0 - phase / step
+    } else if phase > 1.0 - step {
+        (phase - 1.0) / step + 1.0
+    } else {
+        return 0.0;
+    };
+    x * x * x / 6.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::instrument::{Envelope, Instrument, Modulation, Oscillator, Waveform};
+    use crate::sheet::{Modifier, Note, Pitch, Value};
+
+    const RATE: u32 = 44100;
+
+    /// The steady part of a note at `frequency`, without the envelope.
+    fn wave(waveform: Waveform, frequency: f32) -> Vec<f32> {
+        let oscillator = Oscillator::new(waveform).with_envelope(Envelope::new(0.0, 0.0, 1.0, 0.0));
+        let note = Note::new(Pitch::C4, Value::Quarter, Modifier::Natural);
+        oscillator
+            .render(&note, frequency, 1.0, RATE as usize, RATE)
+            .to_vec()
+    }
+
+    /// Amplitude of the component of `samples` at `frequency`.
+    fn magnitude(samples: &[f32], frequency: f32) -> f32 {
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, sample) in samples.iter().enumerate() {
+            let angle = 2.0 * std::f64::consts::PI * frequency as f64 * i as f64 / RATE as f64;
+            re += *sample as f64 * angle.cos();
+            im += *sample as f64 * angle.sin();
+        }
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
+
+    #[test]
+    fn shapes() {
+        // At a low note the waves are close to their ideal shapes.
+        let saw = wave(Waveform::Sawtooth, 100.0);
+        assert!((saw[100] - (2.0 * 100.0 * 100.0 / RATE as f32 - 1.0)).abs() < 1e-3);
+        let square = wave(Waveform::Square, 100.0);
+        assert_eq!(square[100], 1.0);
+        assert_eq!(square[300], -1.0);
+        let triangle = wave(Waveform::Triangle, 100.0);
+        let peak = triangle.iter().cloned().fold(f32::MIN, f32::max);
+        assert!((peak - 1.0).abs() < 1e-2);
+
+        // The narrower the pulse, the more of the fundamental moves into the harmonics.
+        let pulse = |width| Waveform::Pulse {
+            width,
+            modulation: None,
+        };
+        let mean = |samples: &[f32]| samples.iter().sum::<f32>() / samples.len() as f32;
+        let narrow = wave(pulse(0.1), 100.0);
+        assert!(mean(&narrow).abs() < 1e-2);
+        assert!(magnitude(&narrow, 100.0) < magnitude(&square, 100.0));
+
+        let modulated = Waveform::Pulse {
+            width: 0.5,
+            modulation: Some(Modulation {
+                depth: 0.3,
+                rate: 1.0,
+            }),
+        };
+        assert_ne!(wave(modulated, 100.0), square);
+    }
+
+    #[test]
+    fn modulation_from_str() {
+        let modulation = "0.2, 3".parse::<Modulation>().unwrap();
+        assert_eq!(
+            modulation,
+            Modulation {
+                depth: 0.2,
+                rate: 3.0,
+            }
+        );
+        assert!("0.2".parse::<Modulation>().is_err());
+        assert!("0.2,3,1".parse::<Modulation>().is_err());
+        assert!("0.6,3".parse::<Modulation>().is_err());
+        assert!("0.2,-1".parse::<Modulation>().is_err());
+        assert!("0.2,inf".parse::<Modulation>().is_err());
+    }
+
+    #[test]
+    fn band_limited() {
+        // The 7th harmonic of C8, at 29.3 kHz, folds back to 14.8 kHz at 44.1 kHz. Unfiltered,
+        // it would be a seventh of the fundamental in the sawtooth and square waves.
+        let c8 = 4186.0;
+        let folded = RATE as f32 - 7.0 * c8;
+        let pulse = Waveform::Pulse {
+            width: 0.25,
+            modulation: Some(Modulation {
+                depth: 0.15,
+                rate: 0.5,
+            }),
+        };
+        for waveform in [
+            Waveform::Sawtooth,
+            Waveform::Square,
+            Waveform::Triangle,
+            pulse,
+        ] {
+            let samples = wave(waveform, c8);
+            let fundamental = magnitude(&samples, c8);
+            assert!(fundamental > 0.5, "{:?}", waveform);
+            assert!(
+                magnitude(&samples, folded) < fundamental / 20.0,
+                "{:?}",
+                waveform
+            );
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
@@ -7,85 +8,203 @@ use anyhow::Result;
 use ndarray::{Array1, ArrayView1};
 use rodio::Source;
 
-use crate::instrument::SineGenerator;
+use crate::cli::{Command, InstrumentKind, Options};
+use crate::composer::Composer;
+use crate::instrument::{Oscillator, SineGenerator};
+use crate::sheet::Sheet;
+use crate::unroll::unroll;
 
+mod cli;
+mod composer;
+mod error;
 mod instrument;
 mod parse;
 mod sheet;
 mod synth;
-
-const SAMPLE_RATE: u32 = 96000;
+mod unroll;
 
 fn main() -> Result<()> {
-    let mut canon_file = File::open("./sheets/canon_in_d.sht")?;
-    let mut canon_str = String::new();
-    canon_file.read_to_string(&mut canon_str)?;
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
     let parse_timer = Instant::now();
-    let (_, canon_sheet) =
-        parse::sheet(&canon_str).map_err(|_| anyhow::anyhow!("Failed to parse sheet."))?;
+    let mut sheet = read_sheet(options.command.input())?;
+    if let Some(tuning) = options.tuning {
+        sheet.tuning = tuning;
+    }
     let parse_timer = Instant::now() - parse_timer;
-    println!("Parse in {}s", parse_timer.as_secs_f32());
 
-    let compose_timer = Instant::now();
-    let mut generator = SineGenerator::new(SAMPLE_RATE);
-    let sample = generator.compose(&canon_sheet);
-    let compose_timer = Instant::now() - compose_timer;
-    println!("Compose in {}s", compose_timer.as_secs_f32());
+    match &options.command {
+        Command::Check { input } => {
+            println!("{}: ok, {} lines", input.display(), sheet.lines.len());
+        }
+        Command::Render { output, .. } => {
+            println!("Parse in {}s", parse_timer.as_secs_f32());
 
-    let write_timer = Instant::now();
-    write_sample(sample.view())?;
-    let write_timer = Instant::now() - write_timer;
-    println!("Written to file in {}s", write_timer.as_secs_f32());
+            let compose_timer = Instant::now();
+            let sample = compose(&options, &sheet);
+            let compose_timer = Instant::now() - compose_timer;
+            println!("Compose in {}s", compose_timer.as_secs_f32());
 
-    println!(
-        "\nTotal computation time: {}s",
-        (parse_timer + compose_timer + write_timer).as_secs_f32()
-    );
+            let write_timer = Instant::now();
+            write_sample(&options, output, sample.view())?;
+            write_info(output, &sheet)?;
+            let write_timer = Instant::now() - write_timer;
+            println!("Written to file in {}s", write_timer.as_secs_f32());
 
-    // play_sample(sample.view())?;
+            println!(
+                "\nTotal computation time: {}s",
+                (parse_timer + compose_timer + write_timer).as_secs_f32()
+            );
+        }
+        Command::Play { .. } => {
+            let sample = compose(&options, &sheet);
+            play_sample(&options, sample.view())?;
+        }
+    }
 
     Ok(())
 }
 
-fn write_sample(sample: ArrayView1<f32>) -> Result<()> {
+fn read_sheet(path: &Path) -> Result<Sheet> {
+    let mut file = File::open(path)?;
+    let mut input = String::new();
+    file.read_to_string(&mut input)?;
+    parse::sheet(path, &input).map_err(|errors| {
+        for err in errors.iter() {
+            eprintln!("{}\n", err);
+        }
+        anyhow::anyhow!(
+            "Failed to parse {}: {} error(s).",
+            path.display(),
+            errors.len()
+        )
+    })
+}
+
+fn compose(options: &Options, sheet: &Sheet) -> Array1<f32> {
+    let sheet = unroll(sheet);
+    let envelope = options.envelope.unwrap_or_default();
+    match options.instrument {
+        InstrumentKind::Sine => {
+            let sine = SineGenerator::new().with_envelope(envelope);
+            Composer::new(sine, options.sample_rate).compose(&sheet)
+        }
+        InstrumentKind::Oscillator(waveform) => {
+            let oscillator = Oscillator::new(waveform).with_envelope(envelope);
+            Composer::new(oscillator, options.sample_rate).compose(&sheet)
+        }
+    }
+}
+
+fn write_sample(options: &Options, path: &Path, sample: ArrayView1<f32>) -> Result<()> {
     let spec = hound::WavSpec {
         channels: 1,
-        sample_rate: SAMPLE_RATE,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
+        sample_rate: options.sample_rate,
+        bits_per_sample: options.bit_depth,
+        sample_format: match options.bit_depth {
+            32 => hound::SampleFormat::Float,
+            _ => hound::SampleFormat::Int,
+        },
     };
-    let mut writer = hound::WavWriter::create("canon.wav", spec)?;
-    to_i16(sample)
-        .iter()
-        .try_for_each(|x| writer.write_sample(*x))?;
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    match options.bit_depth {
+        32 => sample
+            .iter()
+            .try_for_each(|x| writer.write_sample(*x * options.gain))?,
+        bits => sample
+            .iter()
+            .try_for_each(|x| writer.write_sample(quantize(*x, options.gain, bits)))?,
+    }
     writer.finalize()?;
     Ok(())
 }
 
-fn play_sample(sample: ArrayView1<f32>) -> Result<()> {
-    let source = to_ndaudio(sample);
+/// Appends a RIFF `LIST`/`INFO` chunk carrying the sheet's title and composer to a finalized
+/// WAV file, and patches the RIFF size to cover it.
+fn write_info(path: &Path, sheet: &Sheet) -> Result<()> {
+    let entries = [(b"INAM", &sheet.title), (b"IART", &sheet.composer)];
+    let mut info = b"INFO".to_vec();
+    for (id, text) in entries.iter() {
+        if let Some(text) = text {
+            let mut data = text.as_bytes().to_vec();
+            data.push(0);
+            info.extend_from_slice(*id);
+            info.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            info.extend_from_slice(&data);
+            if data.len() % 2 == 1 {
+                info.push(0);
+            }
+        }
+    }
+    if info.len() == 4 {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    if file.seek(SeekFrom::End(0))? % 2 == 1 {
+        // Chunks start on even offsets, so pad an odd-sized data chunk first.
+        file.write_all(&[0])?;
+    }
+    file.write_all(b"LIST")?;
+    file.write_all(&(info.len() as u32).to_le_bytes())?;
+    file.write_all(&info)?;
+
+    let riff_size = file.seek(SeekFrom::End(0))? - 8;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&(riff_size as u32).to_le_bytes())?;
+    Ok(())
+}
+
+fn play_sample(options: &Options, sample: ArrayView1<f32>) -> Result<()> {
+    let source = to_ndaudio(sample, options.gain, options.sample_rate);
+    let duration = source.duration();
     let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
     stream_handle.play_raw(source.convert_samples())?;
 
-    loop {
-        sleep(Duration::from_millis(10));
-    }
+    sleep(duration + Duration::from_millis(100));
+    Ok(())
 }
 
 pub struct NdAudio {
     data: Array1<i16>,
     pos: usize,
+    sample_rate: u32,
+}
+
+impl NdAudio {
+    fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.data.len() as f64 / self.sample_rate as f64)
+    }
 }
 
-fn to_i16(input: ArrayView1<f32>) -> Array1<i16> {
-    // input.iter().map(|v| *v as i16).collect()
-    input.iter().map(|v| (*v * 5000f32) as i16).collect()
+/// Scales a sample in `[-1, 1]` by `gain` and quantizes it to a signed integer of `bits` width.
+fn quantize(input: f32, gain: f32, bits: u16) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f32;
+    (input * gain * max).clamp(-max, max) as i32
 }
 
-fn to_ndaudio(input: ArrayView1<f32>) -> NdAudio {
+fn to_i16(input: ArrayView1<f32>, gain: f32) -> Array1<i16> {
+    input
+        .iter()
+        .map(|v| quantize(*v, gain, 16) as i16)
+        .collect()
+}
+
+fn to_ndaudio(input: ArrayView1<f32>, gain: f32, sample_rate: u32) -> NdAudio {
     NdAudio {
-        data: to_i16(input),
+        data: to_i16(input, gain),
         pos: 0,
+        sample_rate,
     }
 }
 
@@ -95,10 +214,7 @@ impl Iterator for NdAudio {
     fn next(&mut self) -> Option<Self::Item> {
         let pos = self.pos;
         self.pos += 1;
-        match self.data.get(pos) {
-            Some(v) => Some(*v),
-            None => None,
-        }
+        self.data.get(pos).copied()
     }
 }
 
@@ -112,10 +228,62 @@ impl Source for NdAudio {
     }
 
     fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+        self.sample_rate
     }
 
     fn total_duration(&self) -> Option<Duration> {
-        Some(Duration::from_secs(10))
+        Some(self.duration())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+    use std::fs;
+
+    use crate::sheet::{Sheet, Value};
+    use crate::write_info;
+
+    #[test]
+    fn info_chunk() {
+        let path = std::env::temp_dir().join(format!("compose-info-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        (0..5).try_for_each(|_| writer.write_sample(0i16)).unwrap();
+        writer.finalize().unwrap();
+
+        let mut sheet = Sheet::new(90.0, Value::Eighth, Vec::new());
+        sheet.title = Some("Canon in D".to_string());
+        sheet.composer = Some("Pachelbel".to_string());
+        write_info(&path, &sheet).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let riff_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        assert_eq!(riff_size as usize, bytes.len() - 8);
+        let list = bytes.windows(4).position(|id| id == b"LIST").unwrap();
+        assert_eq!(list % 2, 0);
+        let size = u32::from_le_bytes(bytes[list + 4..list + 8].try_into().unwrap());
+        assert_eq!(list + 8 + size as usize, bytes.len());
+        let expected = [
+            &b"INFO"[..],
+            b"INAM",
+            &11u32.to_le_bytes(),
+            b"Canon in D\0\0",
+            b"IART",
+            &10u32.to_le_bytes(),
+            b"Pachelbel\0",
+        ]
+        .concat();
+        assert_eq!(&bytes[list + 8..], &expected[..]);
+
+        // The samples still read back past the added chunk.
+        let reader = hound::WavReader::new(&bytes[..]);
+        assert_eq!(reader.unwrap().len(), 5);
     }
 }
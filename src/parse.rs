@@ -1,77 +1,1765 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 
-use nom::bytes::complete::{tag, take_while};
-use nom::character::complete::{line_ending, one_of};
-use nom::combinator::{map_res, opt};
-use nom::multi::separated_list0;
-use nom::sequence::tuple;
-use nom::IResult;
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while, take_while1};
+use nom::character::complete::{alpha1, char, not_line_ending, one_of, space0, space1};
+use nom::combinator::{cut, eof, map, map_opt, map_res, opt, peek, recognize, verify};
+use nom::error::{context, VerboseError, VerboseErrorKind};
+use nom::multi::{many_m_n, separated_list0, separated_list1};
+use nom::number::complete::float;
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::{Err, IResult, Offset};
 
-use crate::sheet::{Line, Modifier, Note, Pitch, Sheet, Value};
+use crate::error::ParseError;
+use crate::sheet::{
+    Articulation, Bar, Bpm, Curve, Dynamic, Hairpin, Key, Letter, Line, Mark, MarkKind, Mode,
+    Modifier, Navigation, Note, Pitch, Sheet, Tempo, TimeSignature, Track, Tuplet, Until, Value,
+    MAX_VELOCITY,
+};
 
-pub fn sheet(input: &str) -> IResult<&str, Sheet> {
-    let (input, bpm) = number_usize(input)?;
-    let (input, _) = tag("x")(input)?;
-    let (input, line_value) = value(input)?;
+type Res<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
 
-    let (input, _) = tuple((line_ending, tag("--"), line_ending))(input)?;
-    let (input, lines) = separated_list0(line_ending, line)(input)?;
+/// A single line of the header block above `--`.
+enum Header {
+    Tempo(Bpm, Value),
+    Title(String),
+    Composer(String),
+    Key(Key),
+    Time(TimeSignature),
+    Tuning(f32),
+}
+
+/// Parses a whole sheet read from `path`. Parsing recovers at the end of every malformed token,
+/// so all the problems in the sheet are reported together, each naming the file it was found in.
+/// Included files are looked up next to the file that includes them.
+pub fn sheet(path: &Path, input: &str) -> Result<Sheet, Vec<ParseError>> {
+    sheet_with(input, Some(path), &mut |path| fs::read_to_string(path))
+}
+
+/// Parses a sheet, reading the files it includes with `read`.
+fn sheet_with(
+    input: &str,
+    path: Option<&Path>,
+    read: &mut dyn FnMut(&Path) -> io::Result<String>,
+) -> Result<Sheet, Vec<ParseError>> {
+    let mut errors = Vec::new();
+    let mut source = numbered(input).peekable();
+
+    let mut sheet = Sheet::new(0.0, Value::Whole, Vec::new());
+    let mut header_tempo = None;
+    let mut last = (1, "");
+    let mut separated = false;
+    while let Some(&(number, text)) = source.peek() {
+        if text.trim_end() == "--" {
+            source.next();
+            separated = true;
+            break;
+        }
+        if let Ok((_, comment)) = full_line_comment(text) {
+            source.next();
+            sheet.marks.push(Mark {
+                line: 0,
+                kind: MarkKind::HeaderComment(comment),
+            });
+            continue;
+        }
+        if matches!(line(text), Ok(("", line)) if !line.is_empty()) {
+            // A line of notes means the separator was forgotten, so parse the rest as notes.
+            errors.push(ParseError::new(
+                number,
+                1,
+                text.trim_end(),
+                "`--` separating the header from the notes",
+                text,
+            ));
+            separated = true;
+            break;
+        }
+        source.next();
+        last = (number, text);
+
+        let header = complete(number, text, header(text)).map(|(header, comment)| {
+            if let Some(comment) = comment {
+                sheet.marks.push(Mark {
+                    line: 0,
+                    kind: MarkKind::TrailingHeaderComment(comment),
+                });
+            }
+            header
+        });
+        match header {
+            Ok(Header::Tempo(bpm, line_value)) if header_tempo.is_none() => {
+                header_tempo = Some((bpm, line_value))
+            }
+            Ok(Header::Tempo(..)) => errors.push(ParseError::new(
+                number,
+                1,
+                text.trim_end(),
+                "a single tempo line in the header",
+                text,
+            )),
+            Ok(Header::Title(title)) => sheet.title = Some(title),
+            Ok(Header::Composer(composer)) => sheet.composer = Some(composer),
+            Ok(Header::Key(key)) => sheet.key = Some(key),
+            Ok(Header::Time(time)) => sheet.time = Some(time),
+            Ok(Header::Tuning(tuning)) => sheet.tuning = tuning,
+            Err(err) => {
+                // A tempo followed by something unexpected is still the tempo line, so it is not
+                // reported missing as well.
+                if let (None, Ok((_, found))) = (header_tempo, tempo(text)) {
+                    header_tempo = Some(found);
+                }
+                errors.push(err)
+            }
+        }
+    }
+
+    if !separated {
+        let (number, text) = last;
+        errors.push(ParseError::new(
+            number,
+            text.chars().count() + 1,
+            "",
+            "`--` separating the header from the notes",
+            text,
+        ));
+    }
+
+    match header_tempo {
+        Some((bpm, line_value)) => {
+            sheet.bpm = bpm;
+            sheet.line_value = line_value;
+        }
+        None => {
+            let (number, text) = last;
+            errors.push(ParseError::new(
+                number,
+                text.chars().count() + 1,
+                "",
+                "a tempo line such as 35xs in the header",
+                text,
+            ));
+        }
+    }
+
+    let mut stack = path.map(canonical).into_iter().collect();
+    let mut lines = Vec::new();
+    include(None, source, &mut stack, read, &mut lines, &mut errors);
+    let source = lines;
+    // Everything after the first track header belongs to the tracks.
+    let split = source
+        .iter()
+        .position(|line| is_track_header(&line.text))
+        .unwrap_or(source.len());
+    let mut phrases = phrases(&source, sheet.line_value, &mut errors);
+    let tracks = tracks(&source[split..], sheet.time, &mut phrases, &mut errors);
+    let mut body = Body {
+        key: sheet.key,
+        dynamic: None,
+        hairpin: None,
+        measure: 1,
+        measure_start: 0,
+        jumps: Vec::new(),
+        phrases: phrases.expanded,
+        layers: VecDeque::new(),
+        layered_from: 0,
+        sheet,
+        errors: &mut errors,
+    };
+    for line in source[..split].iter() {
+        if !line.text.trim_start().starts_with('[') {
+            let (reported, jumps) = (body.errors.len(), body.jumps.len());
+            body.line(line.number, &line.text);
+            from_file(&mut body.errors[reported..], line.file());
+            for (_, err) in body.jumps[jumps..].iter_mut() {
+                from_file(std::slice::from_mut(err), line.file());
+            }
+        }
+    }
+    let mut sheet = body.finish();
+    place_tracks(&mut sheet, tracks);
+
+    from_file(
+        &mut errors,
+        path.map(|path| Rc::new(path.to_path_buf())).as_ref(),
+    );
+    if errors.is_empty() {
+        Ok(sheet)
+    } else {
+        Err(errors)
+    }
+}
+
+/// A line of the body of a sheet, from the sheet itself or from a file it includes.
+struct SourceLine {
+    /// The included file the line was read from, or `None` for the sheet itself.
+    file: Option<Rc<PathBuf>>,
+    number: usize,
+    text: String,
+}
+
+impl SourceLine {
+    fn file(&self) -> Option<&Rc<PathBuf>> {
+        self.file.as_ref()
+    }
+}
+
+/// Collects the lines of `file`, replacing every `@include` with the lines of the file it names.
+/// `stack` holds the files being included, to catch files that include themselves.
+fn include<'a>(
+    file: Option<Rc<PathBuf>>,
+    lines: impl Iterator<Item = (usize, &'a str)>,
+    stack: &mut Vec<PathBuf>,
+    read: &mut dyn FnMut(&Path) -> io::Result<String>,
+    out: &mut Vec<SourceLine>,
+    errors: &mut Vec<ParseError>,
+) {
+    let directory = stack
+        .last()
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    for (number, text) in lines {
+        if !text.trim_start().starts_with("@include") {
+            out.push(SourceLine {
+                file: file.clone(),
+                number,
+                text: text.to_string(),
+            });
+            continue;
+        }
+
+        let reported = errors.len();
+        let name = match complete(number, text, include_line(text)) {
+            Ok(name) => name,
+            Err(err) => {
+                errors.push(err);
+                from_file(&mut errors[reported..], file.as_ref());
+                continue;
+            }
+        };
+        let path = canonical(&directory.join(name));
+        let position = &text[text.find('"').unwrap()..];
+        if let Some(start) = stack.iter().position(|included| *included == path) {
+            let cycle = stack[start..]
+                .iter()
+                .chain(Some(&path))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+            errors.push(locate(
+                number,
+                text,
+                position,
+                &format!(
+                    "a file that does not include itself, but {}",
+                    cycle.join(" -> ")
+                ),
+            ));
+        } else {
+            match read(&path) {
+                Ok(input) => {
+                    let included = Rc::new(path.clone());
+                    stack.push(path);
+                    // A file ending in a newline has no empty line after it.
+                    let input = input.strip_suffix('\n').unwrap_or(&input);
+                    include(Some(included), numbered(input), stack, read, out, errors);
+                    stack.pop();
+                }
+                Err(err) => errors.push(locate(
+                    number,
+                    text,
+                    position,
+                    &format!("a file that can be read, but {}: {}", path.display(), err),
+                )),
+            }
+        }
+        from_file(&mut errors[reported..], file.as_ref());
+    }
+}
+
+/// Splits a source into lines numbered from 1.
+fn numbered(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    input
+        .split('\n')
+        .map(|l| l.strip_suffix('\r').unwrap_or(l))
+        .enumerate()
+        .map(|(index, text)| (index + 1, text))
+}
+
+/// The canonical form of `path` when it exists, so the same file is recognised however it is
+/// named.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Attributes the errors that are not yet attributed to a file to `file`.
+fn from_file(errors: &mut [ParseError], file: Option<&Rc<PathBuf>>) {
+    for err in errors.iter_mut().filter(|err| err.file.is_none()) {
+        err.file = file.cloned();
+    }
+}
+
+/// Parses `@include "bass.sht"`, returning the file name.
+fn include_line(input: &str) -> Res<'_, &str> {
+    let (input, name) = delimited(
+        pair(space0, tag("@include")),
+        cut(context(
+            "a file name in quotes such as \"bass.sht\"",
+            preceded(
+                space1,
+                delimited(char('"'), take_while1(|c| c != '"'), char('"')),
+            ),
+        )),
+        space0,
+    )(input)?;
+    let (input, _) = opt(comment)(input)?;
+    Ok((input, name))
+}
+
+/// State carried from line to line while parsing the body of a sheet, below `--`.
+struct Body<'a> {
+    sheet: Sheet,
+    /// The key signature currently in effect.
+    key: Option<Key>,
+    /// The dynamic marking currently in effect.
+    dynamic: Option<Dynamic>,
+    /// The index of the mark of a hairpin waiting for the dynamic marking that ends it, whether
+    /// it is a crescendo, and the error to report if it is never ended.
+    hairpin: Option<(usize, bool, ParseError)>,
+    /// 1-based number of the current measure, counted by bar lines.
+    measure: usize,
+    /// Index of the first line of the current measure.
+    measure_start: usize,
+    /// D.C. and D.S. al fine or al coda, with the error to report if the marks they need are
+    /// missing once the whole sheet has been read.
+    jumps: Vec<(Until, ParseError)>,
+    /// The lines of every phrase, or `None` for phrases whose definition has errors.
+    phrases: HashMap<&'a str, Option<Vec<Line>>>,
+    /// Lines of referenced phrases still to be layered over the lines that follow.
+    layers: VecDeque<Line>,
+    /// Index of the line the latest phrase reference was layered from.
+    layered_from: usize,
+    errors: &'a mut Vec<ParseError>,
+}
+
+impl<'a> Body<'a> {
+    fn line(&mut self, number: usize, text: &'a str) {
+        let trimmed = text.trim_start();
+        let (measure, reported) = (self.measure, self.errors.len());
+        if let Ok(("", comment)) = full_line_comment(text) {
+            self.mark(MarkKind::Comment(comment));
+        } else if trimmed.starts_with('@') {
+            self.directive(number, text);
+        } else if trimmed.starts_with('R') {
+            self.rest(number, text);
+        } else if trimmed.starts_with('|') || trimmed.starts_with(':') {
+            self.bar(number, text);
+        } else {
+            self.notes(number, text);
+        }
+
+        if self.sheet.time.is_some() {
+            for err in self.errors[reported..].iter_mut() {
+                err.measure = Some(measure);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Sheet {
+        self.lay_out_layers();
+
+        let marks = &self.sheet.marks;
+        let has = |navigation| {
+            marks
+                .iter()
+                .any(|mark| mark.kind == MarkKind::Navigation(navigation))
+        };
+        if let Some((_, _, err)) = self.hairpin.take() {
+            self.errors.push(err);
+        }
+        for (until, err) in self.jumps.iter() {
+            let found = match until {
+                Until::End => true,
+                Until::Fine => has(Navigation::Fine),
+                Until::Coda => has(Navigation::ToCoda) && has(Navigation::Coda),
+            };
+            if !found {
+                self.errors.push(err.clone());
+            }
+        }
+        self.sheet
+    }
+
+    /// Adds a line, along with the next line of any phrases being layered over it.
+    fn push(&mut self, mut line: Line) {
+        if let Some(layer) = self.layers.pop_front() {
+            line.merge(layer);
+        }
+        self.sheet.lines.push(line);
+    }
+
+    /// Adds the lines of the phrases still being layered, with nothing over them.
+    fn lay_out_layers(&mut self) {
+        while !self.layers.is_empty() {
+            self.push(Line::new(Vec::new()));
+        }
+    }
+
+    /// Lays out the phrases still being layered when nothing has been written over them since
+    /// they were referenced, so that a bar line or a jump right after a phrase reference comes
+    /// after the lines of the phrase rather than before them.
+    fn close_phrases(&mut self) {
+        if self.sheet.lines.len() == self.layered_from {
+            self.lay_out_layers();
+        }
+    }
+
+    /// Attaches a mark to the next line to be added.
+    fn mark(&mut self, kind: MarkKind) {
+        self.sheet.marks.push(Mark {
+            line: self.sheet.lines.len(),
+            kind,
+        });
+    }
+
+    fn directive(&mut self, number: usize, text: &'a str) {
+        let (directive, comment) = match complete(number, text, directive_line(text)) {
+            Ok(directive) => directive,
+            Err(err) => return self.errors.push(err),
+        };
+        match directive {
+            Directive::Key(key) => {
+                self.key = Some(key);
+                self.mark(MarkKind::Key(key));
+            }
+            Directive::Tempo(tempo) => self.mark(MarkKind::Tempo(tempo)),
+            Directive::Dynamic(dynamic) => {
+                self.end_hairpin(number, text, dynamic);
+                self.dynamic = Some(dynamic);
+                self.mark(MarkKind::Dynamic(dynamic));
+            }
+            Directive::Hairpin(louder, curve) => self.hairpin(number, text, louder, curve),
+            Directive::Navigation(navigation) => {
+                self.close_phrases();
+                self.navigation(number, text, navigation);
+                self.mark(MarkKind::Navigation(navigation));
+            }
+            Directive::Phrase(name, times) => self.phrase(number, text, name, times),
+        }
+        if let Some(comment) = comment {
+            self.mark(MarkKind::Comment(comment));
+        }
+    }
+
+    /// Checks that the places a jump needs exist. The segno must come before a D.S.; the fine
+    /// and coda marks may come anywhere, so they are checked at the end.
+    fn navigation(&mut self, number: usize, text: &str, navigation: Navigation) {
+        let until = match navigation {
+            Navigation::DaCapo(until) => until,
+            Navigation::DalSegno(until) => {
+                let segno = MarkKind::Navigation(Navigation::Segno);
+                if !self.sheet.marks.iter().any(|mark| mark.kind == segno) {
+                    self.errors.push(locate(
+                        number,
+                        text,
+                        text.trim_start(),
+                        "a @segno before this D.S.",
+                    ));
+                }
+                until
+            }
+            _ => return,
+        };
+        let expected = match until {
+            Until::End => return,
+            Until::Fine => "a @fine somewhere in the sheet for al fine",
+            Until::Coda => "@tocoda and @coda somewhere in the sheet for al coda",
+        };
+        self.jumps
+            .push((until, locate(number, text, text.trim_start(), expected)));
+    }
+
+    /// Starts a crescendo, or a diminuendo when not `louder`, from the dynamic in effect. The
+    /// next dynamic marking ends it.
+    fn hairpin(&mut self, number: usize, text: &str, louder: bool, curve: Curve) {
+        let (position, name) = (
+            text.trim_start(),
+            if louder { "crescendo" } else { "diminuendo" },
+        );
+        if self.hairpin.is_some() {
+            return self.errors.push(locate(
+                number,
+                text,
+                position,
+                "a dynamic marking such as @f ending the hairpin before",
+            ));
+        }
+        let from = match self.dynamic {
+            Some(dynamic) => dynamic,
+            None => {
+                return self.errors.push(locate(
+                    number,
+                    text,
+                    position,
+                    &format!("a dynamic marking such as @p before the {}", name),
+                ))
+            }
+        };
+        let expected = format!("a dynamic marking such as @f ending the {}", name);
+        self.hairpin = Some((
+            self.sheet.marks.len(),
+            louder,
+            locate(number, text, position, &expected),
+        ));
+        self.mark(MarkKind::Hairpin(Hairpin {
+            from,
+            // Both filled in by the dynamic marking that ends the hairpin.
+            to: from,
+            lines: 0,
+            curve,
+        }));
+    }
+
+    /// Ends the hairpin in progress, if any, at `dynamic`.
+    fn end_hairpin(&mut self, number: usize, text: &str, dynamic: Dynamic) {
+        let Some((index, louder, _)) = self.hairpin.take() else {
+            return;
+        };
+        let lines = self.sheet.lines.len();
+        let mark = &mut self.sheet.marks[index];
+        let MarkKind::Hairpin(hairpin) = &mut mark.kind else {
+            unreachable!()
+        };
+        let expected = match louder {
+            true if dynamic <= hairpin.from => "a louder dynamic ending the crescendo",
+            false if dynamic >= hairpin.from => "a softer dynamic ending the diminuendo",
+            _ => {
+                hairpin.to = dynamic;
+                hairpin.lines = lines - mark.line;
+                return;
+            }
+        };
+        self.errors
+            .push(locate(number, text, text.trim_start(), expected));
+    }
+
+    /// Layers `times` repetitions of a phrase over the lines from here on.
+    fn phrase(&mut self, number: usize, text: &'a str, name: &'a str, times: usize) {
+        let lines = match self.phrases.get(name) {
+            Some(Some(lines)) => lines.clone(),
+            // The errors in the definition have already been reported.
+            Some(None) => return,
+            None => {
+                let position = &text[text.find('@').unwrap()..];
+                return self.errors.push(locate(
+                    number,
+                    text,
+                    position,
+                    &format!("a directive, or a phrase defined with `[{}] = ...`", name),
+                ));
+            }
+        };
 
-    Ok((input, Sheet::new(bpm as i32, line_value, lines)))
+        self.layered_from = self.sheet.lines.len();
+        for (index, mut line) in lines
+            .iter()
+            .cycle()
+            .take(lines.len() * times)
+            .cloned()
+            .enumerate()
+        {
+            resolve(&mut line, self.key, self.dynamic);
+            match self.layers.get_mut(index) {
+                Some(layer) => layer.merge(line),
+                None => self.layers.push_back(line),
+            }
+        }
+    }
+
+    fn rest(&mut self, number: usize, text: &str) {
+        let (rest, comment) = match complete(number, text, rest_line(text)) {
+            Ok(rest) => rest,
+            Err(err) => return self.errors.push(err),
+        };
+        let count = match rest {
+            Rest::Lines(count) => count,
+            Rest::Value(length) => {
+                let count = length / self.sheet.line_value.divisor();
+                if count < 0.5 || (count - count.round()).abs() > 1e-4 {
+                    return self.errors.push(locate(
+                        number,
+                        text,
+                        text.trim_start(),
+                        "a rest lasting a whole number of lines",
+                    ));
+                }
+                count.round() as usize
+            }
+        };
+
+        self.mark(MarkKind::Rest(count));
+        if let Some(comment) = comment {
+            self.mark(MarkKind::TrailingComment(comment));
+        }
+        for _ in 0..count {
+            self.push(Line::new(Vec::new()));
+        }
+    }
+
+    fn bar(&mut self, number: usize, text: &str) {
+        let (bars, comment) = match complete(number, text, bar_line(text)) {
+            Ok(bar) => bar,
+            Err(err) => return self.errors.push(err),
+        };
+        self.close_phrases();
+        for bar in bars {
+            self.mark(MarkKind::Bar(bar));
+        }
+        if let Some(comment) = comment {
+            self.mark(MarkKind::TrailingComment(comment));
+        }
+
+        // Bar lines written one after another, such as `:|` followed by `|2.`, are one bar line,
+        // and a bar line before the first note, such as an opening `|:`, starts no measure.
+        let lines = self.sheet.lines.len();
+        if self.measure_start == lines {
+            return;
+        }
+        if let Some(time) = self.sheet.time {
+            self.check_measure(number, text, time);
+        }
+        self.measure += 1;
+        self.measure_start = lines;
+    }
+
+    /// Reports a measure ending at this bar line whose lines do not add up to `time`.
+    fn check_measure(&mut self, number: usize, text: &str, time: TimeSignature) {
+        let lines = self.sheet.lines.len() - self.measure_start;
+        let line_value = self.sheet.line_value;
+        if let Some(expected) = misfit_measure(lines, self.measure == 1, line_value, time) {
+            self.errors
+                .push(locate(number, text, text.trim_start(), &expected));
+        }
+    }
+
+    fn notes(&mut self, number: usize, text: &str) {
+        let (mut line, comment) = recover_line(number, text, self.errors);
+        resolve(&mut line, self.key, self.dynamic);
+
+        if let Some(comment) = comment {
+            self.mark(MarkKind::TrailingComment(comment));
+        }
+        self.push(line);
+    }
 }
 
-pub fn line(input: &str) -> IResult<&str, Line> {
-    let (input, values) = separated_list0(tag(" "), note)(input)?;
-    Ok((input, Line(values)))
+/// Resolves unmarked notes through the key signature `key`, and gives the notes without a
+/// velocity of their own the velocity of `dynamic`.
+fn resolve(line: &mut Line, key: Option<Key>, dynamic: Option<Dynamic>) {
+    map_notes(line, |note| note.in_key(key).at_dynamic(dynamic));
 }
 
-pub fn note(input: &str) -> IResult<&str, Note> {
-    let (input, pitch) = pitch(input)?;
-    let (input, value) = value(input)?;
-    let (input, modifier) = opt(modifier)(input)?;
-    let modifier = modifier.map_or(Modifier::Natural, |x| x);
+/// Replaces every note of `line`, tuplets included, with `f` of it.
+fn map_notes(line: &mut Line, f: impl Fn(Note) -> Note) {
+    for note in line.notes.iter_mut() {
+        *note = f(*note);
+    }
+    for tuplet in line.tuplets.iter_mut() {
+        for note in tuplet.notes.iter_mut() {
+            *note = f(*note);
+        }
+    }
+}
+
+/// What a bar line ending a measure of `lines` lines expected instead, if the measure does not
+/// add up to `time`. Only the `first` measure may be shorter, as a pickup.
+fn misfit_measure(
+    lines: usize,
+    first: bool,
+    line_value: Value,
+    time: TimeSignature,
+) -> Option<String> {
+    let length = lines as f32 * line_value.divisor();
+    let pickup = first && length > 0.0 && length < time.measure();
+    if (length - time.measure()).abs() < 1e-4 || pickup {
+        return None;
+    }
+
+    // Counts the measure in the finest of the line value and the signature's unit, then
+    // reduces it back to the signature's unit when it divides evenly.
+    let unit = time.unit.denominator();
+    let mut denominator = line_value.denominator().max(unit);
+    let mut count = lines as u32 * denominator / line_value.denominator();
+    while denominator > unit && count % 2 == 0 {
+        count /= 2;
+        denominator /= 2;
+    }
+    Some(format!(
+        "{}/{} before this bar line, found {}/{}",
+        time.beats, unit, count, denominator
+    ))
+}
+
+/// Reads the tracks following the first `[track name]` header. A track is written horizontally,
+/// like a phrase, over as many lines as it takes; naming a track again carries on where it left
+/// off. A track that does not fill its last line is padded to the end of it. A key or dynamic
+/// such as `@key G major` or `@p` among the items of a track holds for the rest of that track,
+/// in place of those of the sheet, and bar lines `|` are checked against `time`.
+fn tracks<'a>(
+    source: &'a [SourceLine],
+    time: Option<TimeSignature>,
+    phrases: &mut Phrases<'a>,
+    errors: &mut Vec<ParseError>,
+) -> Vec<Track> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut definitions: Vec<Definition> = Vec::new();
+    let mut current = None;
+    for line in source.iter() {
+        let (origin, text) = (Origin::new(line), line.text.as_str());
+        let reported = errors.len();
+        if is_track_header(text) {
+            current = match complete(line.number, text, track_header(text)) {
+                Ok(name) => Some(names.iter().position(|n| *n == name).unwrap_or_else(|| {
+                    names.push(name);
+                    definitions.push(Definition {
+                        items: Vec::new(),
+                        end: origin,
+                    });
+                    names.len() - 1
+                })),
+                Err(err) => {
+                    errors.push(err);
+                    None
+                }
+            };
+        } else if let Some(current) = current.filter(|_| !text.trim_start().starts_with('[')) {
+            match complete(line.number, text, track_items(text)) {
+                Ok(items) => {
+                    let definition = &mut definitions[current];
+                    definition
+                        .items
+                        .extend(items.into_iter().map(|(at, item)| (origin, at, item)));
+                    definition.end = origin;
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        from_file(&mut errors[reported..], line.file());
+    }
+
+    names
+        .into_iter()
+        .zip(definitions)
+        .filter_map(|(name, definition)| {
+            let (lines, _) = lay_out(&definition, time, phrases, &mut Vec::new(), errors)?;
+            Some(Track {
+                name: name.to_string(),
+                lines,
+            })
+        })
+        .collect()
+}
+
+/// Adds the tracks to the sheet, resolving their notes through the key signature and dynamics
+/// in effect at each line, and pads the lines of the sheet to the end of the longest track.
+fn place_tracks(sheet: &mut Sheet, mut tracks: Vec<Track>) {
+    let length = tracks.iter().map(|track| track.lines.len()).max();
+    let length = length.unwrap_or(0).max(sheet.lines.len());
+    let mut marks = sheet.marks.iter().peekable();
+    let (mut key, mut dynamic) = (sheet.key, None);
+    let mut in_effect = Vec::with_capacity(length);
+    for pos in 0..length {
+        while let Some(mark) = marks.next_if(|mark| mark.line <= pos) {
+            match mark.kind {
+                MarkKind::Key(changed) => key = Some(changed),
+                MarkKind::Dynamic(changed) => dynamic = Some(changed),
+                _ => {}
+            }
+        }
+        in_effect.push((key, dynamic));
+    }
+
+    for track in tracks.iter_mut() {
+        for (line, (key, dynamic)) in track.lines.iter_mut().zip(in_effect.iter()) {
+            resolve(line, *key, *dynamic);
+        }
+    }
+    sheet.lines.resize(length, Line::new(Vec::new()));
+    sheet.tracks = tracks;
+}
+
+/// A phrase defined with `[name] = ...`. Phrases are written horizontally: each item starts
+/// where the one before it ends.
+struct Definition<'a> {
+    /// Every item with the line it was written on and the input it was parsed from.
+    items: Vec<(Origin<'a>, &'a str, PhraseItem<'a>)>,
+    /// The last line, where a phrase that does not fill its last line is reported.
+    end: Origin<'a>,
+}
+
+/// The line a phrase item was written on, to locate errors.
+#[derive(Clone, Copy)]
+struct Origin<'a> {
+    file: Option<&'a Rc<PathBuf>>,
+    number: usize,
+    source: &'a str,
+}
+
+impl<'a> Origin<'a> {
+    fn new(line: &'a SourceLine) -> Origin<'a> {
+        Origin {
+            file: line.file(),
+            number: line.number,
+            source: &line.text,
+        }
+    }
+
+    fn error(&self, position: &str, expected: &str) -> ParseError {
+        let mut err = locate(self.number, self.source, position, expected);
+        from_file(std::slice::from_mut(&mut err), self.file);
+        err
+    }
+}
+
+enum PhraseItem<'a> {
+    Note(Note),
+    Tuplet(Tuplet),
+    Rest(Rest),
+    Phrase(&'a str, usize),
+    /// A key signature for the items after it.
+    Key(Key),
+    /// A dynamic marking for the items after it.
+    Dynamic(Dynamic),
+    /// A bar line `|`, only found in tracks.
+    Bar,
+}
+
+/// The phrases defined in a sheet.
+struct Phrases<'a> {
+    line_value: Value,
+    definitions: HashMap<&'a str, Definition<'a>>,
+    /// The lines of every phrase laid out so far, or `None` for phrases with errors.
+    expanded: HashMap<&'a str, Option<Vec<Line>>>,
+}
+
+/// Reads the phrase definitions among the lines of the body and lays every phrase out on the
+/// line grid. Phrases may refer to phrases defined anywhere in the sheet, as long as no phrase
+/// ends up containing itself.
+fn phrases<'a>(
+    source: &'a [SourceLine],
+    line_value: Value,
+    errors: &mut Vec<ParseError>,
+) -> Phrases<'a> {
+    let mut names = Vec::new();
+    let mut definitions = HashMap::new();
+    for line in source.iter() {
+        let (file, number, text) = (line.file(), line.number, line.text.as_str());
+        if !text.trim_start().starts_with('[') || is_track_header(text) {
+            continue;
+        }
+        let reported = errors.len();
+        match complete(number, text, definition_line(text)) {
+            Ok((name, _)) if definitions.contains_key(name) || DIRECTIVES.contains(&name) => {
+                let position = &text[text.find('[').unwrap()..];
+                errors.push(locate(
+                    number,
+                    text,
+                    position,
+                    "a phrase name not already in use",
+                ));
+            }
+            Ok((name, items)) => {
+                let origin = Origin::new(line);
+                names.push(name);
+                definitions.insert(
+                    name,
+                    Definition {
+                        items: items
+                            .into_iter()
+                            .map(|(at, item)| (origin, at, item))
+                            .collect(),
+                        end: origin,
+                    },
+                );
+            }
+            Err(err) => errors.push(err),
+        }
+        from_file(&mut errors[reported..], file);
+    }
+
+    let mut phrases = Phrases {
+        line_value,
+        definitions,
+        expanded: HashMap::new(),
+    };
+    for name in names {
+        expand(name, &mut phrases, &mut Vec::new(), errors);
+    }
+    phrases
+}
+
+/// Lays out the phrase `name` on the line grid, along with every phrase it refers to. `stack`
+/// holds the phrases being expanded, to catch phrases that contain themselves.
+fn expand<'a>(
+    name: &'a str,
+    phrases: &mut Phrases<'a>,
+    stack: &mut Vec<&'a str>,
+    errors: &mut Vec<ParseError>,
+) -> Option<Vec<Line>> {
+    if let Some(lines) = phrases.expanded.get(name) {
+        return lines.clone();
+    }
+    // Taken out while it is laid out, and put back once it is done.
+    let definition = phrases.definitions.remove(name)?;
+    stack.push(name);
+    // Phrases may start anywhere in a measure, so they are not checked against the time.
+    let lines = lay_out(&definition, None, phrases, stack, errors).and_then(|(lines, length)| {
+        let count = length / phrases.line_value.divisor();
+        if (count - count.round()).abs() > 1e-4 {
+            let end = definition.end;
+            errors.push(end.error(
+                &end.source[end.source.len()..],
+                "notes lasting a whole number of lines by the end of the phrase",
+            ));
+            return None;
+        }
+        Some(lines)
+    });
+    stack.pop();
+    phrases.definitions.insert(name, definition);
+    phrases.expanded.insert(name, lines.clone());
+    lines
+}
+
+/// Places the items of `definition` one after another on the line grid, returning the lines
+/// along with the length of the items as a fraction of a whole note. A key or dynamic among the
+/// items resolves the unmarked notes after it, and the measures between bar lines are checked
+/// against `time`.
+fn lay_out<'a>(
+    definition: &Definition<'a>,
+    time: Option<TimeSignature>,
+    phrases: &mut Phrases<'a>,
+    stack: &mut Vec<&'a str>,
+    errors: &mut Vec<ParseError>,
+) -> Option<(Vec<Line>, f32)> {
+    let line_time = phrases.line_value.divisor();
+    let mut lines = Vec::new();
+    let mut position = 0.0;
+    let (mut key, mut dynamic) = (None, None);
+    let (mut measure, mut measure_start) = (1, 0);
+    // Leaves the modifiers alone until a key is given, so the sheet's key may apply later.
+    let resolve_note = |note: Note, key: Option<Key>, dynamic| {
+        let note = if key.is_some() {
+            note.in_key(key)
+        } else {
+            note
+        };
+        note.at_dynamic(dynamic)
+    };
+
+    for (origin, at, item) in definition.items.iter() {
+        let index = position / line_time;
+        if (index - index.round()).abs() > 1e-4 {
+            errors.push(origin.error(
+                at,
+                "to start on the grid, after notes lasting a whole number of lines",
+            ));
+            return None;
+        }
+        let index = index.round() as usize;
+        if lines.len() <= index {
+            lines.resize(index + 1, Line::new(Vec::new()));
+        }
+
+        match item {
+            PhraseItem::Note(note) => {
+                lines[index].notes.push(resolve_note(*note, key, dynamic));
+                position += note.length();
+            }
+            PhraseItem::Tuplet(tuplet) => {
+                let mut tuplet = tuplet.clone();
+                for note in tuplet.notes.iter_mut() {
+                    *note = resolve_note(*note, key, dynamic);
+                }
+                position += tuplet.notes.iter().map(Note::length).sum::<f32>() * tuplet.ratio();
+                lines[index].tuplets.push(tuplet);
+            }
+            PhraseItem::Key(changed) => key = Some(*changed),
+            PhraseItem::Dynamic(changed) => dynamic = Some(*changed),
+            // Bar lines written one after another, or before the first note, start no measure.
+            PhraseItem::Bar if index == measure_start => {}
+            PhraseItem::Bar => {
+                let misfit = time.and_then(|time| {
+                    misfit_measure(
+                        index - measure_start,
+                        measure == 1,
+                        phrases.line_value,
+                        time,
+                    )
+                });
+                if let Some(expected) = misfit {
+                    errors.push(origin.error(at, &expected));
+                }
+                measure += 1;
+                measure_start = index;
+            }
+            PhraseItem::Rest(Rest::Value(length)) => position += length,
+            PhraseItem::Rest(Rest::Lines(count)) => position += *count as f32 * line_time,
+            PhraseItem::Phrase(inner, times) => {
+                if let Some(start) = stack.iter().position(|name| name == inner) {
+                    let mut cycle = stack[start..].to_vec();
+                    cycle.push(inner);
+                    errors.push(origin.error(
+                        at,
+                        &format!(
+                            "a phrase that does not contain itself, but {}",
+                            cycle.join(" -> ")
+                        ),
+                    ));
+                    return None;
+                }
+                if !phrases.definitions.contains_key(inner) {
+                    errors.push(
+                        origin.error(at, &format!("a phrase defined with `[{}] = ...`", inner)),
+                    );
+                    return None;
+                }
+
+                let mut inner_lines = expand(inner, phrases, stack, errors)?;
+                for line in inner_lines.iter_mut() {
+                    map_notes(line, |note| resolve_note(note, key, dynamic));
+                }
+                let count = inner_lines.len() * times;
+                if lines.len() < index + count {
+                    lines.resize(index + count, Line::new(Vec::new()));
+                }
+                for (offset, line) in inner_lines.into_iter().cycle().take(count).enumerate() {
+                    lines[index + offset].merge(line);
+                }
+                position += count as f32 * line_time;
+            }
+        }
+    }
+
+    // A last note that ends part of the way through a line still takes up the line.
+    let count = (position / line_time - 1e-4).ceil().max(0.0);
+    lines.resize(count as usize, Line::new(Vec::new()));
+    Some((lines, position))
+}
+
+/// Parses a phrase definition such as `[ground] = D3q A2q`, along with any trailing comment.
+fn definition_line(input: &str) -> Res<'_, (&str, Vec<(&str, PhraseItem<'_>)>)> {
+    let (input, name) = preceded(
+        space0,
+        delimited(
+            char('['),
+            cut(context("a phrase name such as [ground]", phrase_name)),
+            cut(context("`]` after the phrase name", char(']'))),
+        ),
+    )(input)?;
+    let (input, _) = cut(context(
+        "`=` after the phrase name",
+        delimited(space0, char('='), space0),
+    ))(input)?;
+    let (input, items) = phrase_items(input)?;
+    Ok((input, (name, items)))
+}
+
+/// Whether `text` starts with `[track`, rather than the name of a phrase such as `[tracks]`.
+fn is_track_header(text: &str) -> bool {
+    text.trim_start()
+        .strip_prefix("[track")
+        .map_or(false, |rest| {
+            rest.starts_with(|c: char| c.is_whitespace() || c == ']')
+        })
+}
+
+/// Parses a track header such as `[track bass]`, along with any trailing comment.
+fn track_header(input: &str) -> Res<'_, &str> {
+    let (input, name) = preceded(
+        pair(space0, tag("[track")),
+        terminated(
+            cut(context(
+                "a track name such as [track bass]",
+                preceded(space1, phrase_name),
+            )),
+            cut(context("`]` after the track name", char(']'))),
+        ),
+    )(input)?;
+    let (input, _) = preceded(space0, opt(comment))(input)?;
+    Ok((input, name))
+}
+
+/// Parses the items of a phrase, written one after another, along with any trailing comment.
+fn phrase_items(input: &str) -> Res<'_, Vec<(&str, PhraseItem<'_>)>> {
+    let (input, items) = delimited(space0, separated_list0(space1, phrase_item), space0)(input)?;
+    let (input, _) = opt(comment)(input)?;
+    Ok((input, items))
+}
+
+/// Parses the items of a line of a track, which may also hold bar lines.
+fn track_items(input: &str) -> Res<'_, Vec<(&str, PhraseItem<'_>)>> {
+    let (input, items) = delimited(
+        space0,
+        separated_list0(space1, alt((track_bar, phrase_item))),
+        space0,
+    )(input)?;
+    let (input, _) = opt(comment)(input)?;
+    Ok((input, items))
+}
+
+/// Parses a bar line in a track. Repeats and endings are left to the body, which is what the
+/// navigation of the sheet follows.
+fn track_bar(input: &str) -> Res<'_, (&str, PhraseItem<'_>)> {
+    let (rest, token) = verify(take_while1(|c: char| !c.is_whitespace()), |token: &str| {
+        token.starts_with('|') || token.starts_with(':')
+    })(input)?;
+    if token != "|" {
+        return Err(Err::Failure(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context(
+                    "a bar line `|`, as repeats and endings only go in the body",
+                ),
+            )],
+        }));
+    }
+    Ok((rest, (input, PhraseItem::Bar)))
+}
+
+fn phrase_item(input: &str) -> Res<'_, (&str, PhraseItem<'_>)> {
+    // Keys and dynamics hold for the items after them, while the other directives shape the
+    // whole sheet and only go on lines of the body.
+    if let Ok((rest, name)) = preceded(char('@'), phrase_name)(input) {
+        if name == "key" {
+            let (rest, key) = cut(preceded(space1, key))(rest)?;
+            return Ok((rest, (input, PhraseItem::Key(key))));
+        }
+        if let Some(dynamic) = dynamic_named(name) {
+            return Ok((rest, (input, PhraseItem::Dynamic(dynamic))));
+        }
+        if DIRECTIVES.contains(&name) {
+            return Err(Err::Failure(VerboseError {
+                errors: vec![(
+                    input,
+                    VerboseErrorKind::Context(
+                        "a note, a phrase, a key or a dynamic, as other directives only go on \
+                         lines of the body",
+                    ),
+                )],
+            }));
+        }
+    }
+    let (rest, item) = context(
+        "a note such as D4q#",
+        alt((
+            map(reference, |(name, times)| PhraseItem::Phrase(name, times)),
+            map(rest, PhraseItem::Rest),
+            map(note, PhraseItem::Note),
+            map(tuplet, PhraseItem::Tuplet),
+        )),
+    )(input)?;
+    Ok((rest, (input, item)))
+}
+
+/// Parses a line of notes, skipping any trailing comment.
+pub fn line(input: &str) -> Res<'_, Line> {
+    map(annotated_line, |(line, _)| line)(input)
+}
+
+/// Parses a line of notes along with its trailing comment, if there is one.
+fn annotated_line(input: &str) -> Res<'_, (Line, Option<String>)> {
+    let (input, elements) = terminated(separated_list0(space1, element), space0)(input)?;
+    let (input, comment) = opt(comment)(input)?;
+
+    let mut line = Line::new(Vec::new());
+    for element in elements {
+        match element {
+            Element::Note(note) => line.notes.push(note),
+            Element::Tuplet(tuplet) => line.tuplets.push(tuplet),
+        }
+    }
+    Ok((input, (line, comment)))
+}
+
+/// Anything that can appear between the spaces of a line.
+enum Element {
+    Note(Note),
+    Tuplet(Tuplet),
+}
+
+fn element(input: &str) -> Res<'_, Element> {
+    if input.starts_with('R') {
+        return Err(Err::Failure(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("a note, as a rest goes on a line of its own"),
+            )],
+        }));
+    }
+    context(
+        "a note such as D4q#",
+        alt((map(note, Element::Note), map(tuplet, Element::Tuplet))),
+    )(input)
+}
+
+/// Parses a tuplet group such as `3:[C4e D4e E4e]`.
+pub fn tuplet(input: &str) -> Res<'_, Tuplet> {
+    let start = input;
+    let (input, actual) = terminated(number_usize, char(':'))(input)?;
+    if !(2..=255).contains(&actual) {
+        return Err(Err::Failure(VerboseError {
+            errors: vec![(
+                start,
+                VerboseErrorKind::Context("a tuplet count between 2 and 255"),
+            )],
+        }));
+    }
+    let (input, notes) = cut(context(
+        "a tuplet such as 3:[C4e D4e E4e]",
+        delimited(
+            pair(char('['), space0),
+            separated_list1(space1, note),
+            pair(space0, char(']')),
+        ),
+    ))(input)?;
+    Ok((input, Tuplet::new(actual as u8, notes)))
+}
+
+/// Names of the directives, which cannot be used as phrase names.
+const DIRECTIVES: &[&str] = &[
+    "key", "bpm", "rit", "accel", "segno", "tocoda", "coda", "fine", "dc", "ds", "include", "pp",
+    "p", "mp", "mf", "f", "ff", "cresc", "dim",
+];
+
+/// A line starting with `@` that changes how the lines after it are read.
+enum Directive<'a> {
+    Key(Key),
+    Tempo(Tempo),
+    Navigation(Navigation),
+    Dynamic(Dynamic),
+    /// A crescendo, or a diminuendo when false, with the curve it follows.
+    Hairpin(bool, Curve),
+    /// A phrase layered over the lines that follow, repeated the given number of times.
+    Phrase(&'a str, usize),
+}
+
+fn directive_line(input: &str) -> Res<'_, (Directive<'_>, Option<String>)> {
+    let (input, directive) = delimited(space0, directive, space0)(input)?;
+    let (input, comment) = opt(comment)(input)?;
+    Ok((input, (directive, comment)))
+}
+
+fn directive(input: &str) -> Res<'_, Directive<'_>> {
+    let (rest, _) = char('@')(input)?;
+    let (rest, name) = cut(context(
+        "a directive such as @key A major or @bpm 60",
+        phrase_name,
+    ))(rest)?;
+    if !DIRECTIVES.contains(&name) {
+        let (input, (name, times)) = reference(input)?;
+        return Ok((input, Directive::Phrase(name, times)));
+    }
+    let input = rest;
+    let navigation = match name {
+        "segno" => Some(Navigation::Segno),
+        "tocoda" => Some(Navigation::ToCoda),
+        "coda" => Some(Navigation::Coda),
+        "fine" => Some(Navigation::Fine),
+        _ => None,
+    };
+    if let Some(navigation) = navigation {
+        return Ok((input, Directive::Navigation(navigation)));
+    }
+    if let Some(dynamic) = dynamic_named(name) {
+        return Ok((input, Directive::Dynamic(dynamic)));
+    }
+    if name == "cresc" || name == "dim" {
+        let (input, exponential) = opt(preceded(space1, tag("exp")))(input)?;
+        let curve = match exponential {
+            Some(_) => Curve::Exponential,
+            None => Curve::Linear,
+        };
+        return Ok((input, Directive::Hairpin(name == "cresc", curve)));
+    }
+    if name == "dc" || name == "ds" {
+        let (input, until) = until(input)?;
+        let navigation = match name {
+            "dc" => Navigation::DaCapo(until),
+            _ => Navigation::DalSegno(until),
+        };
+        return Ok((input, Directive::Navigation(navigation)));
+    }
+
+    let (input, _) = cut(space1)(input)?;
+    match name {
+        "key" => cut(map(key, Directive::Key))(input),
+        "bpm" => cut(context(
+            "a tempo above zero such as 60",
+            map(verify(bpm, |bpm| *bpm > 0.0), |bpm| {
+                Directive::Tempo(Tempo::Set(bpm))
+            }),
+        ))(input),
+        slower => cut(map(
+            |input| tempo_ramp(input, slower == "rit"),
+            Directive::Tempo,
+        ))(input),
+    }
+}
+
+fn dynamic_named(name: &str) -> Option<Dynamic> {
+    match name {
+        "pp" => Some(Dynamic::Pianissimo),
+        "p" => Some(Dynamic::Piano),
+        "mp" => Some(Dynamic::MezzoPiano),
+        "mf" => Some(Dynamic::MezzoForte),
+        "f" => Some(Dynamic::Forte),
+        "ff" => Some(Dynamic::Fortissimo),
+        _ => None,
+    }
+}
+
+/// Parses a reference to a phrase such as `@ground` or `@ground x4`.
+fn reference(input: &str) -> Res<'_, (&str, usize)> {
+    let (input, name) = preceded(char('@'), phrase_name)(input)?;
+    let (input, times) = opt(preceded(
+        pair(space1, char('x')),
+        cut(context(
+            "a number of repetitions such as x4",
+            verify(number_usize, |times| *times > 0),
+        )),
+    ))(input)?;
+    Ok((input, (name, times.unwrap_or(1))))
+}
+
+fn phrase_name(input: &str) -> Res<'_, &str> {
+    recognize(pair(
+        alpha1,
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+/// Parses the optional `al fine` or `al coda` after a D.C. or D.S.
+fn until(input: &str) -> Res<'_, Until> {
+    map(
+        opt(preceded(
+            tuple((space1, tag("al"), space1)),
+            cut(context(
+                "`fine` or `coda` after `al`",
+                alt((
+                    map(tag("fine"), |_| Until::Fine),
+                    map(tag("coda"), |_| Until::Coda),
+                )),
+            )),
+        )),
+        |until| until.unwrap_or(Until::End),
+    )(input)
+}
+
+/// Parses the `80->50 over 8` of a ritardando, or of an accelerando when `slower` is false.
+fn tempo_ramp(input: &str, slower: bool) -> Res<'_, Tempo> {
+    let expected = match slower {
+        true => "a slowing tempo change such as 80->50 over 8",
+        false => "a quickening tempo change such as 50->80 over 8",
+    };
+    context(
+        expected,
+        map(
+            verify(
+                tuple((
+                    bpm,
+                    tag("->"),
+                    bpm,
+                    delimited(space1, tag("over"), space1),
+                    number_usize,
+                )),
+                move |(from, _, to, _, lines)| {
+                    *from > 0.0 && *to > 0.0 && *lines > 0 && (from > to) == slower && from != to
+                },
+            ),
+            |(from, _, to, _, lines)| Tempo::Ramp { from, to, lines },
+        ),
+    )(input)
+}
+
+/// Parses a bar line on a line of its own along with its trailing comment. `:|:` ends one
+/// repeat and starts the next, so it stands for two bars.
+fn bar_line(input: &str) -> Res<'_, (Vec<Bar>, Option<String>)> {
+    let (input, bars) = delimited(
+        space0,
+        context(
+            "a bar line such as |, |:, :|, :|x3 or |1.",
+            alt((
+                map(tag(":|:"), |_| vec![Bar::RepeatEnd(2), Bar::RepeatStart]),
+                map(
+                    preceded(
+                        tag(":|"),
+                        opt(preceded(
+                            char('x'),
+                            cut(context(
+                                "a repeat count of at least 2 such as :|x3",
+                                verify(number_usize, |count| *count >= 2),
+                            )),
+                        )),
+                    ),
+                    |count| vec![Bar::RepeatEnd(count.unwrap_or(2) as u32)],
+                ),
+                map(tag("|:"), |_| vec![Bar::RepeatStart]),
+                map(
+                    preceded(
+                        pair(char('|'), peek(take_while1(is_digit))),
+                        cut(context(
+                            "an ending numbered from 1 such as |1.",
+                            terminated(verify(number_usize, |ending| *ending > 0), char('.')),
+                        )),
+                    ),
+                    |ending| vec![Bar::Ending(ending as u32)],
+                ),
+                map(char('|'), |_| vec![Bar::Single]),
+            )),
+        ),
+        space0,
+    )(input)?;
+    let (input, comment) = opt(comment)(input)?;
+    Ok((input, (bars, comment)))
+}
+
+/// An explicit rest, lasting either as long as a note value or for a number of lines.
+enum Rest {
+    /// Length as a fraction of a whole note.
+    Value(f32),
+    Lines(usize),
+}
+
+/// Parses a rest on a line of its own. As every item of a line starts at the same time, a rest
+/// beside notes would silence nothing, so it cannot share its line.
+fn rest_line(input: &str) -> Res<'_, (Rest, Option<String>)> {
+    let (input, rest) = delimited(space0, rest, space0)(input)?;
+    let (input, comment) = opt(comment)(input)?;
+    let (input, _) = cut(context(
+        "the end of the line, as a rest goes on a line of its own",
+        eof,
+    ))(input)?;
+    Ok((input, (rest, comment)))
+}
+
+/// Parses a rest such as `Rq`, `Rh.` or `R*8`.
+fn rest(input: &str) -> Res<'_, Rest> {
+    let (input, _) = char('R')(input)?;
+    cut(context(
+        "a rest such as Rq or R*8",
+        alt((
+            map(
+                preceded(
+                    char('*'),
+                    cut(context(
+                        "a number of lines such as R*8",
+                        verify(number_usize, |count| *count > 0),
+                    )),
+                ),
+                Rest::Lines,
+            ),
+            map(pair(value, many_m_n(0, 2, char('.'))), |(value, dots)| {
+                Rest::Value(value.dotted(dots.len() as u8))
+            }),
+        )),
+    ))(input)
+}
+
+/// Parses a `;` or `//` comment running to the end of the line, returning its trimmed text.
+fn comment(input: &str) -> Res<'_, String> {
+    let (input, _) = alt((tag(";"), tag("//")))(input)?;
+    map(not_line_ending, |text: &str| text.trim().to_string())(input)
+}
+
+fn full_line_comment(input: &str) -> Res<'_, String> {
+    preceded(space0, comment)(input)
+}
+
+pub fn note(input: &str) -> Res<'_, Note> {
+    let (input, _) = context("a note such as D4q#", peek(one_of("ABCDEFG")))(input)?;
+    let (input, (pitch, value, dots, modifier, articulation, velocity, tie)) = cut(tuple((
+        pitch,
+        value,
+        many_m_n(0, 2, char('.')),
+        opt(modifier),
+        opt(articulation),
+        opt(velocity),
+        opt(char('~')),
+    )))(input)?;
+    let modifier = modifier.map_or(Modifier::Unspecified, |x| x);
 
     Ok((
         input,
         Note {
-            pitch,
-            value,
-            modifier,
+            dots: dots.len() as u8,
+            tie: tie.is_some(),
+            velocity,
+            articulation,
+            ..Note::new(pitch, value, modifier)
         },
     ))
 }
 
-fn value(input: &str) -> IResult<&str, Value> {
-    let (input, indicator) = one_of("whqes")(input)?;
+fn tempo(input: &str) -> Res<'_, (Bpm, Value)> {
+    let (input, bpm) = context("a tempo such as 35xs", verify(bpm, |bpm| *bpm > 0.0))(input)?;
+    let (input, _) = cut(context(
+        "`x` between the tempo and the line value",
+        char('x'),
+    ))(input)?;
+    let (input, line_value) = cut(value)(input)?;
+    Ok((input, (bpm, line_value)))
+}
+
+/// Parses a line of the header, along with any trailing comment.
+fn header(input: &str) -> Res<'_, (Header, Option<String>)> {
+    let (input, header) = context(
+        "a tempo such as 35xs or a field such as `title: Canon in D`",
+        alt((map(tempo, |(bpm, value)| Header::Tempo(bpm, value)), field)),
+    )(input)?;
+    // Titles and composers run to the end of the line, the other fields may end in a comment.
+    let (input, comment) = preceded(space0, opt(comment))(input)?;
+    let (input, _) = cut(context("a comment or the end of the line", eof))(input)?;
+    Ok((input, (header, comment)))
+}
+
+fn field(input: &str) -> Res<'_, Header> {
+    let (input, name) = context(
+        "a header field (title, composer, key, time or tuning)",
+        verify(alpha1, |name: &str| {
+            matches!(name, "title" | "composer" | "key" | "time" | "tuning")
+        }),
+    )(input)?;
+    let (input, _) = cut(pair(context("`:` after the field name", char(':')), space0))(input)?;
+
+    match name {
+        "title" => map(text, Header::Title)(input),
+        "composer" => map(text, Header::Composer)(input),
+        "key" => cut(map(key, Header::Key))(input),
+        "time" => cut(map(time_signature, Header::Time))(input),
+        _ => cut(map(tuning, Header::Tuning))(input),
+    }
+}
+
+fn text(input: &str) -> Res<'_, String> {
+    map(not_line_ending, |text: &str| text.trim_end().to_string())(input)
+}
+
+fn key(input: &str) -> Res<'_, Key> {
+    context(
+        "a key such as `D major` or `F# minor`",
+        map(
+            tuple((
+                letter,
+                opt(modifier),
+                space1,
+                alt((
+                    map(tag("major"), |_| Mode::Major),
+                    map(tag("minor"), |_| Mode::Minor),
+                )),
+            )),
+            |(tonic, modifier, _, mode)| Key {
+                tonic,
+                modifier: modifier.unwrap_or(Modifier::Natural),
+                mode,
+            },
+        ),
+    )(input)
+}
+
+fn time_signature(input: &str) -> Res<'_, TimeSignature> {
+    context(
+        "a time signature such as 3/4",
+        map_opt(
+            tuple((number_usize, char('/'), number_usize)),
+            |(beats, _, unit)| {
+                let unit = match unit {
+                    1 => Value::Whole,
+                    2 => Value::Half,
+                    4 => Value::Quarter,
+                    8 => Value::Eighth,
+                    16 => Value::Sixteenth,
+                    32 => Value::ThirtySecond,
+                    64 => Value::SixtyFourth,
+                    _ => return None,
+                };
+                match beats {
+                    0 => None,
+                    beats => Some(TimeSignature {
+                        beats: beats as u32,
+                        unit,
+                    }),
+                }
+            },
+        ),
+    )(input)
+}
+
+fn tuning(input: &str) -> Res<'_, f32> {
+    context(
+        "a tuning frequency in Hz such as 440",
+        verify(float, |tuning: &f32| *tuning > 0.0),
+    )(input)
+}
+
+fn letter(input: &str) -> Res<'_, Letter> {
+    let (input, letter) = one_of("ABCDEFG")(input)?;
+    let out = match letter {
+        'A' => Letter::A,
+        'B' => Letter::B,
+        'C' => Letter::C,
+        'D' => Letter::D,
+        'E' => Letter::E,
+        'F' => Letter::F,
+        'G' => Letter::G,
+        _ => unreachable!(),
+    };
+    Ok((input, out))
+}
+
+/// Parses a line of notes, resuming after each malformed token so that every problem on the
+/// line is reported rather than only the first.
+fn recover_line(
+    number: usize,
+    source: &str,
+    errors: &mut Vec<ParseError>,
+) -> (Line, Option<String>) {
+    let mut rest = source;
+    loop {
+        match complete(number, source, annotated_line(rest)) {
+            Ok(line) => return line,
+            Err(err) => {
+                let offset = source
+                    .char_indices()
+                    .nth(err.column - 1)
+                    .map_or(source.len(), |(offset, _)| offset);
+                rest = source[offset..]
+                    .trim_start_matches(|c: char| !c.is_whitespace())
+                    .trim_start();
+                errors.push(err);
+            }
+        }
+    }
+}
+
+/// Converts the result of parsing one source line into a located `ParseError`, treating any
+/// unconsumed input as an error.
+fn complete<T>(number: usize, source: &str, result: Res<T>) -> Result<T, ParseError> {
+    let (position, expected) = match result {
+        Ok(("", value)) => return Ok(value),
+        Ok((rest, _)) if source[..source.offset(rest)].ends_with(char::is_whitespace) => {
+            (rest, "a note such as D4q#")
+        }
+        Ok((rest, _)) => (rest, "a space or the end of the line"),
+        Err(Err::Error(err)) | Err(Err::Failure(err)) => {
+            let expected = err
+                .errors
+                .iter()
+                .find_map(|(_, kind)| match kind {
+                    VerboseErrorKind::Context(context) => Some(*context),
+                    _ => None,
+                })
+                .unwrap_or("valid input");
+            let position = err.errors.first().map_or(source, |(rest, _)| *rest);
+            (position, expected)
+        }
+        Err(Err::Incomplete(_)) => (&source[source.len()..], "more input"),
+    };
+
+    Err(locate(number, source, position, expected))
+}
+
+/// Builds an error for the token starting at `position`, which must be a slice of `source`.
+fn locate(number: usize, source: &str, position: &str, expected: &str) -> ParseError {
+    let offset = source.offset(position);
+    let token = position
+        .split(char::is_whitespace)
+        .next()
+        .unwrap_or_default();
+    ParseError::new(
+        number,
+        source[..offset].chars().count() + 1,
+        token,
+        expected,
+        source,
+    )
+}
+
+fn value(input: &str) -> Res<'_, Value> {
+    let (input, indicator) =
+        context("a note value (w, h, q, e, s, t or x)", one_of("whqestx"))(input)?;
     let out = match indicator {
         'w' => Value::Whole,
         'h' => Value::Half,
         'q' => Value::Quarter,
         'e' => Value::Eighth,
         's' => Value::Sixteenth,
+        't' => Value::ThirtySecond,
+        'x' => Value::SixtyFourth,
         _ => unreachable!(),
     };
     Ok((input, out))
 }
 
-fn modifier(input: &str) -> IResult<&str, Modifier> {
-    let (input, indicator) = one_of("#b")(input)?;
+fn modifier(input: &str) -> Res<'_, Modifier> {
+    let (input, indicator) = alt((tag("##"), tag("#"), tag("n"), tag("bb"), tag("b")))(input)?;
     let out = match indicator {
-        '#' => Modifier::Sharp,
-        'b' => Modifier::Flat,
+        "##" => Modifier::DoubleSharp,
+        "#" => Modifier::Sharp,
+        "n" => Modifier::Natural,
+        "bb" => Modifier::DoubleFlat,
+        "b" => Modifier::Flat,
         _ => unreachable!(),
     };
     Ok((input, out))
 }
 
-fn pitch(input: &str) -> IResult<&str, Pitch> {
-    let (input, letter) = one_of("ABCDEFG")(input)?;
-    let (input, number) = take_while(is_digit)(input)?;
-    Ok((input, match_pitch(letter, number)))
+fn articulation(input: &str) -> Res<'_, Articulation> {
+    let (input, indicator) = one_of("'_>-^")(input)?;
+    let out = match indicator {
+        '\'' => Articulation::Staccato,
+        '_' => Articulation::Legato,
+        '>' => Articulation::Accent,
+        '-' => Articulation::Tenuto,
+        '^' => Articulation::Marcato,
+        _ => unreachable!(),
+    };
+    Ok((input, out))
+}
+
+/// Parses a velocity suffix such as `v90`.
+fn velocity(input: &str) -> Res<'_, u8> {
+    preceded(
+        char('v'),
+        cut(context(
+            "a velocity from 1 to 127 such as v90",
+            map_opt(number_usize, |velocity| {
+                (1..=MAX_VELOCITY as usize)
+                    .contains(&velocity)
+                    .then_some(velocity as u8)
+            }),
+        )),
+    )(input)
+}
+
+fn pitch(input: &str) -> Res<'_, Pitch> {
+    context(
+        "a pitch between A0 and C8",
+        map_opt(
+            pair(one_of("ABCDEFG"), take_while(is_digit)),
+            |(letter, number)| match_pitch(letter, number),
+        ),
+    )(input)
 }
 
-fn match_pitch(letter: char, number: &str) -> Pitch {
-    match (letter, number) {
+fn match_pitch(letter: char, number: &str) -> Option<Pitch> {
+    let pitch = match (letter, number) {
         ('A', "0") => Pitch::A0,
         ('B', "0") => Pitch::B0,
         ('C', "1") => Pitch::C1,
@@ -132,64 +1820,220 @@ fn match_pitch(letter: char, number: &str) -> Pitch {
         ('B', "7") => Pitch::B7,
         ('C', "8") => Pitch::C8,
 
-        _ => unreachable!(),
-    }
+        _ => return None,
+    };
+    Some(pitch)
+}
+
+/// Parses a tempo such as `90` or `72.5`.
+fn bpm(input: &str) -> Res<'_, Bpm> {
+    map_res(
+        recognize(pair(
+            take_while1(is_digit),
+            opt(pair(char('.'), take_while1(is_digit))),
+        )),
+        Bpm::from_str,
+    )(input)
 }
 
-fn number_usize(input: &str) -> IResult<&str, usize> {
+fn number_usize(input: &str) -> Res<'_, usize> {
     map_res(take_while(is_digit), usize::from_str)(input)
 }
 
 fn is_digit(input: char) -> bool {
-    match input {
-        '1' => true,
-        '2' => true,
-        '3' => true,
-        '4' => true,
-        '5' => true,
-        '6' => true,
-        '7' => true,
-        '8' => true,
-        '9' => true,
-        '0' => true,
-        _ => false,
-    }
+    input.is_ascii_digit()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parse::{line, note, number_usize, sheet};
-    use crate::sheet::{Line, Modifier, Note, Pitch, Sheet, Value};
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+
+    use crate::error::ParseError;
+    use crate::parse::{line, note, number_usize, sheet_with, tuplet};
+    use crate::sheet::{
+        Articulation, Bar, Curve, Dynamic, Hairpin, Key, Letter, Line, Mark, MarkKind, Mode,
+        Modifier, Navigation, Note, Pitch, Sheet, Tempo, TimeSignature, Tuplet, Until, Value,
+    };
+
+    /// Parses a sheet that is not read from a file and cannot include any.
+    fn sheet(input: &str) -> Result<Sheet, Vec<ParseError>> {
+        sheet_with(input, None, &mut |_| Err(io::ErrorKind::NotFound.into()))
+    }
 
     #[test]
     fn basic_sheet() {
         let input = "90xe\n--\nD3e F5h\nA4e";
         let expected = Sheet::new(
-            90f32,
+            90.0,
             Value::Eighth,
             vec![
-                Line(vec![
-                    Note {
-                        pitch: Pitch::D3,
-                        value: Value::Eighth,
-                        modifier: Modifier::Natural,
-                    },
-                    Note {
-                        pitch: Pitch::F5,
-                        value: Value::Half,
-                        modifier: Modifier::Natural,
-                    },
+                Line::new(vec![
+                    Note::new(Pitch::D3, Value::Eighth, Modifier::Natural),
+                    Note::new(Pitch::F5, Value::Half, Modifier::Natural),
                 ]),
-                Line(vec![Note {
-                    pitch: Pitch::A4,
-                    value: Value::Eighth,
-                    modifier: Modifier::Natural,
-                }]),
+                Line::new(vec![Note::new(Pitch::A4, Value::Eighth, Modifier::Natural)]),
             ],
         );
-        let (input, actual) = sheet(input).unwrap();
+        let actual = sheet(input).unwrap();
         assert_eq!(actual, expected);
-        assert_eq!(input, "");
+    }
+
+    #[test]
+    fn sheet_header() {
+        let input = "; arranged\ntitle: Canon in D\ncomposer: Johann Pachelbel \n35xs ; slow\nkey: F# minor // home key\ntime: 3/4;\ntuning: 415 ; baroque\n--\nD3e";
+        let mut expected = Sheet::new(
+            35.0,
+            Value::Sixteenth,
+            vec![Line::new(vec![Note::new(
+                Pitch::D3,
+                Value::Eighth,
+                Modifier::Natural,
+            )])],
+        );
+        expected.title = Some("Canon in D".to_string());
+        expected.composer = Some("Johann Pachelbel".to_string());
+        expected.key = Some(Key {
+            tonic: Letter::F,
+            modifier: Modifier::Sharp,
+            mode: Mode::Minor,
+        });
+        expected.time = Some(TimeSignature {
+            beats: 3,
+            unit: Value::Quarter,
+        });
+        expected.tuning = 415.0;
+        expected.marks = vec![
+            MarkKind::HeaderComment("arranged".to_string()),
+            MarkKind::TrailingHeaderComment("slow".to_string()),
+            MarkKind::TrailingHeaderComment("home key".to_string()),
+            MarkKind::TrailingHeaderComment("".to_string()),
+            MarkKind::TrailingHeaderComment("baroque".to_string()),
+        ]
+        .into_iter()
+        .map(|kind| Mark { line: 0, kind })
+        .collect();
+        assert_eq!(sheet(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn bad_header() {
+        let input = "90xe\nstyle: baroque\ntime: 3/5\n--\nD3e";
+        let expected = vec![
+            ParseError::new(
+                2,
+                1,
+                "style:",
+                "a header field (title, composer, key, time or tuning)",
+                "style: baroque",
+            ),
+            ParseError::new(3, 7, "3/5", "a time signature such as 3/4", "time: 3/5"),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+
+        let input = "90xe slow
+key: D major minor
+--
+D3e";
+        let expected = vec![
+            ParseError::new(
+                1,
+                6,
+                "slow",
+                "a comment or the end of the line",
+                "90xe slow",
+            ),
+            ParseError::new(
+                2,
+                14,
+                "minor",
+                "a comment or the end of the line",
+                "key: D major minor",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn unknown_pitch() {
+        let input = "90xe\n--\nD3e F5h\nA4e H4q";
+        let expected = ParseError::new(4, 5, "H4q", "a note such as D4q#", "A4e H4q");
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn out_of_range_pitch() {
+        let input = "90xe\n--\nD3e C9h#";
+        let expected = ParseError::new(3, 5, "C9h#", "a pitch between A0 and C8", "D3e C9h#");
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn bad_value() {
+        let input = "90xe\n--\nD3z";
+        let expected = ParseError::new(3, 3, "z", "a note value (w, h, q, e, s, t or x)", "D3z");
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn missing_separator() {
+        let input = "90xe\nD3e";
+        let expected = ParseError::new(
+            2,
+            1,
+            "D3e",
+            "`--` separating the header from the notes",
+            "D3e",
+        );
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+
+        let expected = ParseError::new(
+            1,
+            5,
+            "",
+            "`--` separating the header from the notes",
+            "90xe",
+        );
+        assert_eq!(sheet("90xe").unwrap_err(), vec![expected]);
+        let expected = ParseError::new(
+            2,
+            9,
+            "",
+            "`--` separating the header from the notes",
+            "title: x",
+        );
+        assert_eq!(sheet("90xe\ntitle: x").unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn all_errors() {
+        let input = "90xe\n--\nD3e H4q F5z\nA4e\nC9e D4q#";
+        let expected = vec![
+            ParseError::new(3, 5, "H4q", "a note such as D4q#", "D3e H4q F5z"),
+            ParseError::new(
+                3,
+                11,
+                "z",
+                "a note value (w, h, q, e, s, t or x)",
+                "D3e H4q F5z",
+            ),
+            ParseError::new(5, 1, "C9e", "a pitch between A0 and C8", "C9e D4q#"),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn render_error() {
+        let error = ParseError::new(12, 5, "H4q", "a note such as D4q#", "A4e H4q");
+        let expected = "\
+error: unexpected `H4q`, expected a note such as D4q#
+   --> 12:5
+   |
+12 | A4e H4q
+   |     ^^^";
+        assert_eq!(error.to_string(), expected);
     }
 
     #[test]
@@ -200,17 +2044,848 @@ mod test {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn dotted_tied_note() {
+        let (_, actual) = note("D4q.#~").unwrap();
+        let expected = Note {
+            dots: 1,
+            tie: true,
+            ..Note::new(Pitch::D4, Value::Quarter, Modifier::Sharp)
+        };
+        assert_eq!(actual, expected);
+
+        let (_, actual) = note("A3h..").unwrap();
+        assert_eq!(actual.dots, 2);
+        assert_eq!(actual.length(), 0.875);
+    }
+
+    #[test]
+    fn articulations() {
+        let articulation = |input| note(input).unwrap().1.articulation;
+        assert_eq!(articulation("D4q'"), Some(Articulation::Staccato));
+        assert_eq!(articulation("D4q_"), Some(Articulation::Legato));
+        assert_eq!(articulation("D4q>"), Some(Articulation::Accent));
+        assert_eq!(articulation("D4q-"), Some(Articulation::Tenuto));
+        assert_eq!(articulation("D4q^"), Some(Articulation::Marcato));
+        assert_eq!(articulation("D4q"), None);
+
+        let (_, actual) = note("F4e.#>v100~").unwrap();
+        let expected = Note {
+            dots: 1,
+            tie: true,
+            velocity: Some(100),
+            articulation: Some(Articulation::Accent),
+            ..Note::new(Pitch::F4, Value::Eighth, Modifier::Sharp)
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn short_values() {
+        let input = "35xx\n--\nD4t# A3x\n";
+        let actual = sheet(input).unwrap();
+        assert_eq!(actual.line_value, Value::SixtyFourth);
+        assert_eq!(
+            actual.lines[0],
+            Line::new(vec![
+                Note::new(Pitch::D4, Value::ThirtySecond, Modifier::Sharp),
+                Note::new(Pitch::A3, Value::SixtyFourth, Modifier::Natural),
+            ])
+        );
+        assert_eq!(sheet("35xt\n--\n").unwrap().line_value, Value::ThirtySecond);
+    }
+
+    #[test]
+    fn tuplet_line() {
+        let input = "D3q 3:[C4e D4e E4e]";
+        let mut expected = Line::new(vec![Note::new(
+            Pitch::D3,
+            Value::Quarter,
+            Modifier::Unspecified,
+        )]);
+        expected.tuplets.push(Tuplet::new(
+            3,
+            vec![
+                Note::new(Pitch::C4, Value::Eighth, Modifier::Unspecified),
+                Note::new(Pitch::D4, Value::Eighth, Modifier::Unspecified),
+                Note::new(Pitch::E4, Value::Eighth, Modifier::Unspecified),
+            ],
+        ));
+        let (rest, actual) = line(input).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(rest, "");
+
+        let offsets = actual
+            .placed_notes()
+            .iter()
+            .map(|(offset, _, length)| (*offset * 12.0, *length * 12.0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offsets,
+            vec![(0.0, 3.0), (0.0, 1.0), (1.0, 1.0), (2.0, 1.0)]
+        );
+
+        // A duplet of eighths takes the time of three.
+        let (_, actual) = line("2:[C4e D4e]").unwrap();
+        let offsets = actual
+            .placed_notes()
+            .iter()
+            .map(|(offset, _, length)| (*offset * 16.0, *length * 16.0))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![(0.0, 3.0), (3.0, 3.0)]);
+    }
+
+    #[test]
+    fn bad_tuplet() {
+        let (_, actual) = tuplet("255:[C4e]").unwrap();
+        assert_eq!((actual.actual, actual.normal), (255, 128));
+
+        let input = "90xe\n--\n1:[C4e] 3:[C4e D4z]";
+        let expected = vec![
+            ParseError::new(
+                3,
+                1,
+                "1:[C4e]",
+                "a tuplet count between 2 and 255",
+                "1:[C4e] 3:[C4e D4z]",
+            ),
+            ParseError::new(
+                3,
+                18,
+                "z]",
+                "a note value (w, h, q, e, s, t or x)",
+                "1:[C4e] 3:[C4e D4z]",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn double_accidentals() {
+        let input = "F4q## G4q## B4ebb D4x## C4sb";
+        let expected = Line::new(vec![
+            Note::new(Pitch::F4, Value::Quarter, Modifier::DoubleSharp),
+            Note::new(Pitch::G4, Value::Quarter, Modifier::DoubleSharp),
+            Note::new(Pitch::B4, Value::Eighth, Modifier::DoubleFlat),
+            Note::new(Pitch::D4, Value::SixtyFourth, Modifier::DoubleSharp),
+            Note::new(Pitch::C4, Value::Sixteenth, Modifier::Flat),
+        ]);
+        let (rest, actual) = line(input).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(rest, "");
+
+        // `x` is only ever the sixty-fourth note value.
+        let input = "90xe\n--\nD4xx";
+        let expected = ParseError::new(3, 4, "x", "a space or the end of the line", "D4xx");
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn key_signature() {
+        let input = "90xe\nkey: D major\n--\nF4q C5q F4qn\n@key Bb minor\nD4e G4e C4en";
+        let actual = sheet(input).unwrap();
+        let modifiers = actual
+            .lines
+            .iter()
+            .flat_map(|line| line.notes.iter().map(|note| note.modifier))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            modifiers,
+            vec![
+                Modifier::Sharp,
+                Modifier::Sharp,
+                Modifier::Natural,
+                Modifier::Flat,
+                Modifier::Flat,
+                Modifier::Natural,
+            ]
+        );
+        assert_eq!(actual.marks.len(), 1);
+        assert_eq!(actual.marks[0].line, 1);
+    }
+
+    #[test]
+    fn rests() {
+        let input = "90xe\n--\nD3e\nRq ; breathe\nR*3\nA4e\n  Rh.";
+        let actual = sheet(input).unwrap();
+        assert_eq!(actual.lines.len(), 1 + 2 + 3 + 1 + 6);
+        assert!(actual.lines[1..6].iter().all(|line| line.is_empty()));
+        let rests = actual
+            .marks
+            .iter()
+            .filter_map(|mark| match mark.kind {
+                MarkKind::Rest(count) => Some((mark.line, count)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rests, vec![(1, 2), (3, 3), (7, 6)]);
+    }
+
+    #[test]
+    fn bad_rests() {
+        let input = "90xe\n--\nRs\nR*0\nRz";
+        let expected = vec![
+            ParseError::new(3, 1, "Rs", "a rest lasting a whole number of lines", "Rs"),
+            ParseError::new(4, 3, "0", "a number of lines such as R*8", "R*0"),
+            ParseError::new(5, 2, "z", "a note value (w, h, q, e, s, t or x)", "Rz"),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+
+        let input = "90xe\n--\nC4q Rq\nRq C4q";
+        let expected = vec![
+            ParseError::new(
+                3,
+                5,
+                "Rq",
+                "a note, as a rest goes on a line of its own",
+                "C4q Rq",
+            ),
+            ParseError::new(
+                4,
+                4,
+                "C4q",
+                "the end of the line, as a rest goes on a line of its own",
+                "Rq C4q",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn tempo_directives() {
+        let input = "92.5xe\n--\nD3e\n@bpm 60\nD3e\n@rit 80->50.5 over 8 ; ending\nD3e";
+        let actual = sheet(input).unwrap();
+        assert_eq!(actual.bpm, 92.5);
+        let tempi = actual
+            .marks
+            .iter()
+            .filter_map(|mark| match mark.kind {
+                MarkKind::Tempo(tempo) => Some((mark.line, tempo)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (1, Tempo::Set(60.0)),
+            (
+                2,
+                Tempo::Ramp {
+                    from: 80.0,
+                    to: 50.5,
+                    lines: 8,
+                },
+            ),
+        ];
+        assert_eq!(tempi, expected);
+
+        let input = "90xe\n--\n@bpm 0\n@rit 50->80 over 8\n@accel 50->80 over 0\n@presto";
+        let expected = vec![
+            ParseError::new(3, 6, "0", "a tempo above zero such as 60", "@bpm 0"),
+            ParseError::new(
+                4,
+                6,
+                "50->80",
+                "a slowing tempo change such as 80->50 over 8",
+                "@rit 50->80 over 8",
+            ),
+            ParseError::new(
+                5,
+                8,
+                "50->80",
+                "a quickening tempo change such as 50->80 over 8",
+                "@accel 50->80 over 0",
+            ),
+            ParseError::new(
+                6,
+                1,
+                "@presto",
+                "a directive, or a phrase defined with `[presto] = ...`",
+                "@presto",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn dynamics() {
+        let input = "90xq\n--\nC4q\n@p\nD4q E4qv100\n[up] = F4q\n@up\n@ff ; loud\nG4q\n\
+                     [track high]\nA4q A4q A4qv20 A4q\n[track low]\n@pp B3q @up\n@f B3q\n@mf\nB3q";
+        let actual = sheet(input).unwrap();
+        let velocities = |lines: &[Line]| {
+            lines
+                .iter()
+                .map(|line| {
+                    line.notes
+                        .iter()
+                        .map(|note| (note.pitch, note.velocity))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            velocities(&actual.lines),
+            vec![
+                vec![(Pitch::C4, None)],
+                vec![(Pitch::D4, Some(49)), (Pitch::E4, Some(100))],
+                vec![(Pitch::G4, Some(112)), (Pitch::F4, Some(49))],
+                vec![],
+            ]
+        );
+        assert_eq!(
+            velocities(&actual.tracks[0].lines),
+            vec![
+                vec![(Pitch::A4, None)],
+                vec![(Pitch::A4, Some(49))],
+                vec![(Pitch::A4, Some(20))],
+                vec![(Pitch::A4, Some(112))],
+            ]
+        );
+        assert_eq!(
+            velocities(&actual.tracks[1].lines),
+            vec![
+                vec![(Pitch::B3, Some(33))],
+                vec![(Pitch::F4, Some(33))],
+                vec![(Pitch::B3, Some(96))],
+                vec![(Pitch::B3, Some(80))],
+            ]
+        );
+        assert!(actual.marks.contains(&Mark {
+            line: 2,
+            kind: MarkKind::Dynamic(Dynamic::Fortissimo),
+        }));
+
+        let input = "90xq\n--\nC4qv0 D4q#v128~\nE4qv";
+        let expected = vec![
+            ParseError::new(
+                3,
+                5,
+                "0",
+                "a velocity from 1 to 127 such as v90",
+                "C4qv0 D4q#v128~",
+            ),
+            ParseError::new(
+                3,
+                12,
+                "128~",
+                "a velocity from 1 to 127 such as v90",
+                "C4qv0 D4q#v128~",
+            ),
+            ParseError::new(4, 5, "", "a velocity from 1 to 127 such as v90", "E4qv"),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+
+        let input = "90xq\n--\nC4q\n[track low]\nB3q @cresc B3q";
+        let expected = ParseError::new(
+            5,
+            5,
+            "@cresc",
+            "a note, a phrase, a key or a dynamic, as other directives only go on lines of the body",
+            "B3q @cresc B3q",
+        );
+        assert_eq!(sheet(input).unwrap_err(), vec![expected]);
+    }
+
+    #[test]
+    fn hairpins() {
+        let input = "90xq\n--\n@p\nC4q\n@cresc exp\nD4q\nE4q\n@f\nF4q\n@dim\nG4q\n@mp\nA4q";
+        let actual = sheet(input).unwrap();
+        let hairpins = actual
+            .marks
+            .iter()
+            .filter_map(|mark| match mark.kind {
+                MarkKind::Hairpin(hairpin) => Some((mark.line, hairpin)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (
+                1,
+                Hairpin {
+                    from: Dynamic::Piano,
+                    to: Dynamic::Forte,
+                    lines: 2,
+                    curve: Curve::Exponential,
+                },
+            ),
+            (
+                4,
+                Hairpin {
+                    from: Dynamic::Forte,
+                    to: Dynamic::MezzoPiano,
+                    lines: 1,
+                    curve: Curve::Linear,
+                },
+            ),
+        ];
+        assert_eq!(hairpins, expected);
+        let velocities = actual
+            .lines
+            .iter()
+            .map(|line| line.notes[0].velocity)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            velocities,
+            vec![Some(49), Some(49), Some(49), Some(96), Some(96), Some(64)]
+        );
+
+        let input = "90xq\n--\n@cresc\n@f\n@cresc\nC4q\n@p\n@dim\n@dim\nC4q";
+        let expected = vec![
+            ParseError::new(
+                3,
+                1,
+                "@cresc",
+                "a dynamic marking such as @p before the crescendo",
+                "@cresc",
+            ),
+            ParseError::new(7, 1, "@p", "a louder dynamic ending the crescendo", "@p"),
+            ParseError::new(
+                9,
+                1,
+                "@dim",
+                "a dynamic marking such as @f ending the hairpin before",
+                "@dim",
+            ),
+            ParseError::new(
+                8,
+                1,
+                "@dim",
+                "a dynamic marking such as @f ending the diminuendo",
+                "@dim",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn bar_lines() {
+        let input =
+            "time: 3/4\n90xq\n--\nD3q\n|\nD3q\nD3q\nD3q\n| ; two\nD3q\nD3q\n|\n\nR*3\nD3q\n|\nD3q";
+        let mut errors = sheet(input).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ParseError {
+                    measure: Some(3),
+                    ..ParseError::new(12, 1, "|", "3/4 before this bar line, found 2/4", "|")
+                },
+                ParseError {
+                    measure: Some(4),
+                    ..ParseError::new(16, 1, "|", "3/4 before this bar line, found 5/4", "|")
+                },
+            ]
+        );
+        let expected = "\
+error: unexpected `|`, expected 3/4 before this bar line, found 5/4
+   --> 16:1
+   |
+16 | |
+   | ^
+   = note: in measure 4";
+        assert_eq!(errors.pop().unwrap().to_string(), expected);
+
+        let input = "time: 6/8\n90xs\n--\nD3e\nD3e\nD3e\n|\nD3e\nC9e";
+        let expected = vec![ParseError {
+            measure: Some(2),
+            ..ParseError::new(9, 1, "C9e", "a pitch between A0 and C8", "C9e")
+        }];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+
+        let input = "time: 4/4\n90xq\n--\n|:\nD3q\nD3q\nD3q\nD3q\n:|\nD3q\nC9q";
+        let expected = vec![ParseError {
+            measure: Some(2),
+            ..ParseError::new(11, 1, "C9q", "a pitch between A0 and C8", "C9q")
+        }];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+        let bars = sheet("90xq\n--\nD3q\n|\nD3q D3h")
+            .unwrap()
+            .marks
+            .into_iter()
+            .filter(|mark| mark.kind == MarkKind::Bar(Bar::Single))
+            .count();
+        assert_eq!(bars, 1);
+    }
+
+    #[test]
+    fn repeats_and_navigation() {
+        let input =
+            "90xq\n--\n|:\nD3q\n:|x3 ; three times\n|2.\nD3q\n:|:\n@segno\n@ds al fine\n@fine";
+        let actual = sheet(input).unwrap();
+        let expected = vec![
+            (0, MarkKind::Bar(Bar::RepeatStart)),
+            (1, MarkKind::Bar(Bar::RepeatEnd(3))),
+            (1, MarkKind::TrailingComment("three times".to_string())),
+            (1, MarkKind::Bar(Bar::Ending(2))),
+            (2, MarkKind::Bar(Bar::RepeatEnd(2))),
+            (2, MarkKind::Bar(Bar::RepeatStart)),
+            (2, MarkKind::Navigation(Navigation::Segno)),
+            (2, MarkKind::Navigation(Navigation::DalSegno(Until::Fine))),
+            (2, MarkKind::Navigation(Navigation::Fine)),
+        ];
+        let marks = actual
+            .marks
+            .into_iter()
+            .map(|mark| (mark.line, mark.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(marks, expected);
+
+        let input = "90xq\n--\n:|x1\n|0.\n@ds\n@dc al coda\n@coda\n@dc al segno";
+        let expected = vec![
+            ParseError::new(
+                3,
+                4,
+                "1",
+                "a repeat count of at least 2 such as :|x3",
+                ":|x1",
+            ),
+            ParseError::new(4, 2, "0.", "an ending numbered from 1 such as |1.", "|0."),
+            ParseError::new(5, 1, "@ds", "a @segno before this D.S.", "@ds"),
+            ParseError::new(8, 8, "segno", "`fine` or `coda` after `al`", "@dc al segno"),
+            ParseError::new(
+                6,
+                1,
+                "@dc",
+                "@tocoda and @coda somewhere in the sheet for al coda",
+                "@dc al coda",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn phrases() {
+        let input = "90xe\nkey: D major\n--\n[ground] = D3e Rq @arpeggio\n[arpeggio] = F3e 3:[A3s C4s D4s]\n\
+                     @ground x2 ; twice\nA4q\nR*2\nB4e";
+        let actual = sheet(input).unwrap();
+        let note = |pitch, value, modifier| Note::new(pitch, value, modifier);
+        let d = note(Pitch::D3, Value::Eighth, Modifier::Natural);
+        let f = note(Pitch::F3, Value::Eighth, Modifier::Sharp);
+        let a = note(Pitch::A4, Value::Quarter, Modifier::Natural);
+        let b = note(Pitch::B4, Value::Eighth, Modifier::Natural);
+        let arpeggio = Tuplet::new(
+            3,
+            vec![
+                note(Pitch::A3, Value::Sixteenth, Modifier::Natural),
+                note(Pitch::C4, Value::Sixteenth, Modifier::Sharp),
+                note(Pitch::D4, Value::Sixteenth, Modifier::Natural),
+            ],
+        );
+        let with_arpeggio = |mut line: Line| {
+            line.tuplets.push(arpeggio.clone());
+            line
+        };
+        let expected = vec![
+            Line::new(vec![a, d]),
+            Line::new(vec![]),
+            Line::new(vec![]),
+            Line::new(vec![b, f]),
+            with_arpeggio(Line::new(vec![])),
+            Line::new(vec![d]),
+            Line::new(vec![]),
+            Line::new(vec![]),
+            Line::new(vec![f]),
+            with_arpeggio(Line::new(vec![])),
+        ];
+        assert_eq!(actual.lines, expected);
+
+        // A bar line or a jump right after a reference follows the lines of the phrase, while
+        // one written over the phrase falls among them.
+        let input = "time: 4/4\n90xq\n--\n[ground] = C3q D3q E3q F3q\n@ground\n|\n@ground x2\n\
+                     G4q\nG4q\nG4q\nG4q\n|\n@ground\n@fine";
+        let actual = sheet(input).unwrap();
+        assert_eq!(actual.lines.len(), 12);
+        assert_eq!(actual.lines[4].notes.len(), 2);
+        assert_eq!(actual.lines[8].notes.len(), 2);
+        let marks = actual
+            .marks
+            .into_iter()
+            .map(|mark| (mark.line, mark.kind))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (4, MarkKind::Bar(Bar::Single)),
+            (8, MarkKind::Bar(Bar::Single)),
+            (12, MarkKind::Navigation(Navigation::Fine)),
+        ];
+        assert_eq!(marks, expected);
+
+        let input = "90xe\n--\n[a] = C4e @b\n[b] = @a\n[c] = D4q @missing\n[d] = D4s\n\
+                     [e] = D4s D4e\n[a] = C4e\n[key] = C4e\n@d\n@nothing x2";
+        let expected = vec![
+            ParseError::new(8, 1, "[a]", "a phrase name not already in use", "[a] = C4e"),
+            ParseError::new(
+                9,
+                1,
+                "[key]",
+                "a phrase name not already in use",
+                "[key] = C4e",
+            ),
+            ParseError::new(
+                4,
+                7,
+                "@a",
+                "a phrase that does not contain itself, but a -> b -> a",
+                "[b] = @a",
+            ),
+            ParseError::new(
+                5,
+                11,
+                "@missing",
+                "a phrase defined with `[missing] = ...`",
+                "[c] = D4q @missing",
+            ),
+            ParseError::new(
+                6,
+                10,
+                "",
+                "notes lasting a whole number of lines by the end of the phrase",
+                "[d] = D4s",
+            ),
+            ParseError::new(
+                7,
+                11,
+                "D4e",
+                "to start on the grid, after notes lasting a whole number of lines",
+                "[e] = D4s D4e",
+            ),
+            ParseError::new(
+                11,
+                1,
+                "@nothing",
+                "a directive, or a phrase defined with `[nothing] = ...`",
+                "@nothing x2",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn tracks() {
+        let input = "90xq\nkey: D major\n--\nC4q\n@key C major\nR*2\n[walk] = D3h\n\
+                     [track bass]\n@walk F3q ; walking\n\nG3h\n[track violin]\nF4h\n\
+                     [track bass]\nA3q";
+        let actual = sheet(input).unwrap();
+        let note = |pitch, value, modifier| Note::new(pitch, value, modifier);
+        let natural = |pitch, value| note(pitch, value, Modifier::Natural);
+        let empty = || Line::new(vec![]);
+        assert_eq!(actual.lines.len(), 6);
+        assert_eq!(
+            actual.lines[0],
+            Line::new(vec![note(Pitch::C4, Value::Quarter, Modifier::Sharp)])
+        );
+        assert!(actual.lines[1..].iter().all(|line| *line == empty()));
+
+        assert_eq!(actual.tracks.len(), 2);
+        assert_eq!(actual.tracks[0].name, "bass");
+        assert_eq!(
+            actual.tracks[0].lines,
+            vec![
+                Line::new(vec![natural(Pitch::D3, Value::Half)]),
+                empty(),
+                Line::new(vec![natural(Pitch::F3, Value::Quarter)]),
+                Line::new(vec![natural(Pitch::G3, Value::Half)]),
+                empty(),
+                Line::new(vec![natural(Pitch::A3, Value::Quarter)]),
+            ]
+        );
+        assert_eq!(actual.tracks[1].name, "violin");
+        assert_eq!(
+            actual.tracks[1].lines,
+            vec![
+                Line::new(vec![note(Pitch::F4, Value::Half, Modifier::Sharp)]),
+                empty(),
+            ]
+        );
+
+        let input = "90xq\n--\nC4q\n[track bass]\nD3q D3e\n[track]\n[track 2nd]\nE3q";
+        let expected = vec![
+            ParseError::new(6, 7, "]", "a track name such as [track bass]", "[track]"),
+            ParseError::new(
+                7,
+                8,
+                "2nd]",
+                "a track name such as [track bass]",
+                "[track 2nd]",
+            ),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn track_bars_and_keys() {
+        let input = "90xq\ntime: 2/4\nkey: D major\n--\nC4h\n[tracks] = F4q\n\
+                     [track bass]\nF3q | @key C major F3q F3q | F3q @tracks\n";
+        let actual = sheet(input).unwrap();
+        let note = |pitch, modifier| Note::new(pitch, Value::Quarter, modifier);
+        assert_eq!(
+            actual.tracks[0].lines,
+            vec![
+                Line::new(vec![note(Pitch::F3, Modifier::Sharp)]),
+                Line::new(vec![note(Pitch::F3, Modifier::Natural)]),
+                Line::new(vec![note(Pitch::F3, Modifier::Natural)]),
+                Line::new(vec![note(Pitch::F3, Modifier::Natural)]),
+                Line::new(vec![note(Pitch::F4, Modifier::Natural)]),
+            ]
+        );
+
+        let input = "90xq\ntime: 2/4\n--\nC4h\n[track bass]\nC3q C3q | C3q |\n\
+                     C3q |: C3q\nC3q @bpm 120\n";
+        let expected =
+            vec![
+            ParseError::new(
+                7,
+                5,
+                "|:",
+                "a bar line `|`, as repeats and endings only go in the body",
+                "C3q |: C3q",
+            ),
+            ParseError::new(
+                8,
+                5,
+                "@bpm",
+                "a note, a phrase, a key or a dynamic, as other directives only go on lines of \
+                 the body",
+                "C3q @bpm 120",
+            ),
+            ParseError::new(6, 15, "|", "2/4 before this bar line, found 1/4", "C3q C3q | C3q |"),
+        ];
+        assert_eq!(sheet(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn includes() {
+        let files = vec![
+            ("/music/parts/bass.sht", "D3q\n@include \"fill.sht\" ; fill"),
+            ("/music/parts/fill.sht", "E3q\nC9q"),
+            ("/music/loop.sht", "F3q\n@include \"main.sht\""),
+            ("/music/intro.sht", "E3q\n\nF3q"),
+            ("/music/outro.sht", "A3q\r\n"),
+        ]
+        .into_iter()
+        .map(|(path, input)| (PathBuf::from(path), input.to_string()))
+        .collect::<HashMap<_, _>>();
+        let mut read = |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        };
+        let input = "90xq\n--\nC3q\n@include \"parts/bass.sht\"\n@include \"loop.sht\"\n\
+                     @include \"missing.sht\"\n@include bass.sht";
+        let errors = sheet_with(input, Some(Path::new("/music/main.sht")), &mut read).unwrap_err();
+        let file = |path: &str, err: ParseError| ParseError {
+            file: Some(Rc::new(PathBuf::from(path))),
+            ..err
+        };
+        let expected = vec![
+            file(
+                "/music/loop.sht",
+                ParseError::new(
+                    2,
+                    10,
+                    "\"main.sht\"",
+                    "a file that does not include itself, but /music/main.sht -> \
+                     /music/loop.sht -> /music/main.sht",
+                    "@include \"main.sht\"",
+                ),
+            ),
+            file(
+                "/music/main.sht",
+                ParseError::new(
+                    6,
+                    10,
+                    "\"missing.sht\"",
+                    "a file that can be read, but /music/missing.sht: not found",
+                    "@include \"missing.sht\"",
+                ),
+            ),
+            file(
+                "/music/main.sht",
+                ParseError::new(
+                    7,
+                    10,
+                    "bass.sht",
+                    "a file name in quotes such as \"bass.sht\"",
+                    "@include bass.sht",
+                ),
+            ),
+            // Files are all included before the lines are read, so these errors come last.
+            file(
+                "/music/parts/fill.sht",
+                ParseError::new(2, 1, "C9q", "a pitch between A0 and C8", "C9q"),
+            ),
+        ];
+        assert_eq!(errors, expected);
+        assert!(errors[3]
+            .to_string()
+            .contains(" --> /music/parts/fill.sht:2:1\n"));
+
+        let input = "90xq\n--\nC3q\n@include \"intro.sht\"\nG3q\n@include \"outro.sht\"\nB3q";
+        let actual = sheet_with(input, Some(Path::new("/music/main.sht")), &mut read).unwrap();
+        let pitches = actual
+            .lines
+            .iter()
+            .map(|line| line.notes.first().map(|note| note.pitch))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            Some(Pitch::C3),
+            Some(Pitch::E3),
+            None,
+            Some(Pitch::F3),
+            Some(Pitch::G3),
+            Some(Pitch::A3),
+            Some(Pitch::B3),
+        ];
+        assert_eq!(pitches, expected);
+    }
+
     #[test]
     fn basic_line() {
         let input = "D3e F5h";
-        let expected = Line(vec![
-            Note::new(Pitch::D3, Value::Eighth, Modifier::Natural),
-            Note::new(Pitch::F5, Value::Half, Modifier::Natural),
+        let expected = Line::new(vec![
+            Note::new(Pitch::D3, Value::Eighth, Modifier::Unspecified),
+            Note::new(Pitch::F5, Value::Half, Modifier::Unspecified),
         ]);
         let (_, actual) = line(input).unwrap();
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn commented_line() {
+        let input = "D3e F5h ; measure 12";
+        let expected = Line::new(vec![
+            Note::new(Pitch::D3, Value::Eighth, Modifier::Unspecified),
+            Note::new(Pitch::F5, Value::Half, Modifier::Unspecified),
+        ]);
+        let (rest, actual) = line(input).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(rest, "");
+    }
+
+    #[test]
+    fn commented_sheet() {
+        let input = "; Pachelbel\n90xe\n--\n// variation 3\nD3e // bass enters\n\n  ; rest\nA4e";
+        let actual = sheet(input).unwrap();
+        let expected = vec![
+            Mark {
+                line: 0,
+                kind: MarkKind::HeaderComment("Pachelbel".to_string()),
+            },
+            Mark {
+                line: 0,
+                kind: MarkKind::Comment("variation 3".to_string()),
+            },
+            Mark {
+                line: 0,
+                kind: MarkKind::TrailingComment("bass enters".to_string()),
+            },
+            Mark {
+                line: 2,
+                kind: MarkKind::Comment("rest".to_string()),
+            },
+        ];
+        assert_eq!(actual.lines.len(), 3);
+        assert_eq!(actual.lines[1], Line::new(vec![]));
+        assert_eq!(actual.marks, expected);
+    }
+
     #[test]
     fn number() {
         let input = "90f";
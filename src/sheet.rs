@@ -1,42 +1,562 @@
-use nom::lib::std::collections::HashSet;
+use nom::lib::std::collections::HashMap;
 
-pub type BPM = i32;
+/// Tempo in whole notes per minute.
+pub type Bpm = f64;
+
+/// Highest velocity a note can be played with.
+pub const MAX_VELOCITY: u8 = 127;
+
+/// Concert pitch of A4 in Hz, used when the sheet does not set a `tuning`.
+pub const DEFAULT_TUNING: f32 = 440.0;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Sheet {
-    pub bpm: BPM,
+    pub bpm: Bpm,
     pub line_value: Value,
     pub lines: Vec<Line>,
+    pub marks: Vec<Mark>,
+    /// Voices written apart from the lines, each on the same grid.
+    pub tracks: Vec<Track>,
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    pub key: Option<Key>,
+    pub time: Option<TimeSignature>,
+    pub tuning: f32,
 }
 
 impl Sheet {
-    pub fn new(bpm: BPM, line_value: Value, lines: Vec<Line>) -> Sheet {
+    pub fn new(bpm: Bpm, line_value: Value, lines: Vec<Line>) -> Sheet {
         Sheet {
             bpm,
             line_value,
             lines,
+            marks: Vec::new(),
+            tracks: Vec::new(),
+            title: None,
+            composer: None,
+            key: None,
+            time: None,
+            tuning: DEFAULT_TUNING,
         }
     }
 
-    pub fn unique_notes(&self) -> HashSet<Note> {
-        let mut out = HashSet::new();
-        for line in self.lines.iter() {
-            for note in line.0.iter() {
-                out.insert(*note);
+    /// Flattens the lines into the notes to be sounded, merging each tied note with the note of
+    /// the same pitch that starts where it ends.
+    pub fn events(&self) -> Vec<Event> {
+        events(&self.lines, self.line_value)
+    }
+
+    /// Works out when every line starts from the header tempo and the tempo marks.
+    pub fn tempo_map(&self) -> TempoMap {
+        let mut tempi = Vec::with_capacity(self.lines.len());
+        let mut marks = self.marks.iter().peekable();
+        let mut current = self.bpm;
+        let mut ramp: Option<(f64, f64, usize, usize)> = None;
+
+        for pos in 0..self.lines.len() {
+            while let Some(mark) = marks.next_if(|mark| mark.line <= pos) {
+                match mark.kind {
+                    MarkKind::Tempo(Tempo::Set(bpm)) => {
+                        current = bpm;
+                        ramp = None;
+                    }
+                    MarkKind::Tempo(Tempo::Ramp { from, to, lines }) => {
+                        ramp = Some((from, to, pos, lines));
+                    }
+                    _ => {}
+                }
+            }
+            match ramp {
+                Some((from, to, start, lines)) => {
+                    let step = (to - from) / lines as f64;
+                    let done = (pos - start) as f64;
+                    tempi.push((from + step * done, from + step * (done + 1.0)));
+                    current = from + step * (done + 1.0);
+                    if pos + 1 - start == lines {
+                        ramp = None;
+                    }
+                }
+                None => tempi.push((current, current)),
             }
         }
+
+        TempoMap::new(self.line_value, tempi, current)
+    }
+}
+
+/// A voice written on its own, horizontally, and played along with the lines of the sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub name: String,
+    pub lines: Vec<Line>,
+}
+
+impl Track {
+    /// Flattens the lines of the track into the notes to be sounded, as [`Sheet::events`] does.
+    pub fn events(&self, line_value: Value) -> Vec<Event> {
+        events(&self.lines, line_value)
+    }
+}
+
+/// Flattens `lines` into events. Ties only carry on between notes of the same lines.
+fn events(lines: &[Line], line_value: Value) -> Vec<Event> {
+    let line_time = line_value.divisor();
+    let mut events: Vec<Event> = Vec::new();
+    let mut ties: HashMap<(Pitch, Modifier), usize> = HashMap::new();
+
+    for (pos, line) in lines.iter().enumerate() {
+        let line_start = pos as f32 * line_time;
+        for (offset, note, length) in line.placed_notes() {
+            let key = (note.pitch, note.modifier);
+            let start = line_start + offset;
+            let tied = ties
+                .get(&key)
+                .copied()
+                .filter(|index| (events[*index].end(line_time) - start).abs() < 1e-4);
+            let index = match tied {
+                Some(index) => {
+                    ties.remove(&key);
+                    events[index].length += length;
+                    index
+                }
+                None => {
+                    events.push(Event {
+                        line: pos,
+                        offset,
+                        note,
+                        length,
+                    });
+                    events.len() - 1
+                }
+            };
+            if note.tie {
+                ties.insert(key, index);
+            }
+        }
+        // Ties that should have been continued by now never will be.
+        ties.retain(|_, index| events[*index].end(line_time) > line_start + line_time - 1e-4);
+    }
+
+    events
+}
+
+/// Converts positions on the grid of lines into time, following the tempo changes of a sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoMap {
+    line_value: Value,
+    /// Tempo at the start and at the end of every line. Tempo changes linearly within a line.
+    tempi: Vec<(f64, f64)>,
+    /// Start of every line in seconds, followed by the end of the last line.
+    starts: Vec<f64>,
+    /// Tempo kept after the last line, for notes that ring past it.
+    last: f64,
+}
+
+impl TempoMap {
+    fn new(line_value: Value, tempi: Vec<(f64, f64)>, last: f64) -> TempoMap {
+        let mut starts = vec![0.0];
+        for (pos, tempo) in tempi.iter().enumerate() {
+            let end = starts[pos] + Self::elapsed(line_value, *tempo, 1.0);
+            starts.push(end);
+        }
+        TempoMap {
+            line_value,
+            tempi,
+            starts,
+            last,
+        }
+    }
+
+    /// Time in seconds at `position`, counted in lines from the start of the sheet. Positions
+    /// between lines fall inside the tempo change of the line they are on.
+    pub fn time(&self, position: f64) -> f64 {
+        let pos = (position.max(0.0) as usize).min(self.tempi.len());
+        let tempo = self
+            .tempi
+            .get(pos)
+            .copied()
+            .unwrap_or((self.last, self.last));
+        self.starts[pos] + Self::elapsed(self.line_value, tempo, position - pos as f64)
+    }
+
+    /// Index of the sample nearest to `position`, counted in lines from the start of the sheet.
+    /// Every position is measured from the start of the piece, so rounding never accumulates
+    /// from one line to the next.
+    pub fn sample(&self, position: f64, sample_rate: u32) -> usize {
+        (self.time(position) * sample_rate as f64).round() as usize
+    }
+
+    /// Seconds taken by the first `fraction` of a line whose tempo moves from `tempo.0` to
+    /// `tempo.1` over the whole line.
+    fn elapsed(line_value: Value, (from, to): (f64, f64), fraction: f64) -> f64 {
+        let line_time = 60.0 * line_value.divisor() as f64;
+        if (to - from).abs() < 1e-6 {
+            line_time * fraction / from
+        } else {
+            // Integrates 1 / bpm over a tempo that changes at a steady rate along the line.
+            let bpm = from + (to - from) * fraction;
+            line_time * (bpm / from).ln() / (to - from)
+        }
+    }
+}
+
+/// A note placed on the timeline, with any notes tied to it folded into its length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    /// Index of the line the note starts on.
+    pub line: usize,
+    /// Distance from the start of the line as a fraction of a whole note, non-zero only for
+    /// notes inside tuplets.
+    pub offset: f32,
+    pub note: Note,
+    /// Sounding length as a fraction of a whole note.
+    pub length: f32,
+}
+
+impl Event {
+    fn end(&self, line_time: f32) -> f32 {
+        self.line as f32 * line_time + self.offset + self.length
+    }
+}
+
+/// One slot of the sheet's grid. Every note and tuplet in a line starts at the same time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub notes: Vec<Note>,
+    pub tuplets: Vec<Tuplet>,
+}
+
+impl Line {
+    pub fn new(notes: Vec<Note>) -> Line {
+        Line {
+            notes,
+            tuplets: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty() && self.tuplets.is_empty()
+    }
+
+    /// Adds the notes and tuplets of `other`, which start at the same time as this line.
+    pub fn merge(&mut self, other: Line) {
+        self.notes.extend(other.notes);
+        self.tuplets.extend(other.tuplets);
+    }
+
+    /// Every note in the line with its offset from the start of the line and its sounding
+    /// length, both as fractions of a whole note, ordered by offset.
+    pub fn placed_notes(&self) -> Vec<(f32, Note, f32)> {
+        let mut out = self
+            .notes
+            .iter()
+            .map(|note| (0.0, *note, note.length()))
+            .collect::<Vec<_>>();
+        for tuplet in self.tuplets.iter() {
+            let mut offset = 0.0;
+            for note in tuplet.notes.iter() {
+                let length = note.length() * tuplet.ratio();
+                out.push((offset, *note, length));
+                offset += length;
+            }
+        }
+        out.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
         out
     }
 }
 
+/// A group of notes played one after another in the time of `normal` notes of their written
+/// value, such as three eighths in the time of two.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuplet {
+    pub actual: u8,
+    pub normal: u8,
+    pub notes: Vec<Note>,
+}
+
+impl Tuplet {
+    /// Groups `actual` notes into the time of the largest power of two below `actual`, which is
+    /// the conventional reading of a tuplet written with a single number. The exception is the
+    /// duplet, played in the time of three as in compound meters.
+    pub fn new(actual: u8, notes: Vec<Note>) -> Tuplet {
+        if actual == 2 {
+            return Tuplet {
+                actual,
+                normal: 3,
+                notes,
+            };
+        }
+        // Counted in a wider type, as doubling past 128 would overflow.
+        let mut normal: u16 = 1;
+        while normal * 2 < actual as u16 {
+            normal *= 2;
+        }
+        Tuplet {
+            actual,
+            normal: normal as u8,
+            notes,
+        }
+    }
+
+    pub fn ratio(&self) -> f32 {
+        self.normal as f32 / self.actual as f32
+    }
+}
+
+/// Something positioned in the stream of lines that is not itself a note. Tempo marks shape the
+/// timing of the composition; the others are kept so that tooling can reproduce the sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mark {
+    /// Index into `Sheet::lines` of the line this mark is attached to.
+    pub line: usize,
+    pub kind: MarkKind,
+}
+
 #[derive(Clone, Debug, PartialEq)]
-pub struct Line(pub Vec<Note>);
+pub enum MarkKind {
+    /// A comment on a line of its own in the header, always at line 0.
+    HeaderComment(String),
+    /// A comment following a field of the header, always at line 0.
+    TrailingHeaderComment(String),
+    /// A comment on a line of its own, placed before `line`.
+    Comment(String),
+    /// A comment following the notes of `line`.
+    TrailingComment(String),
+    /// A change of key signature taking effect from `line`.
+    Key(Key),
+    /// An explicit rest written on a line of its own, silencing this many lines from `line`.
+    Rest(usize),
+    /// A change of tempo taking effect from `line`.
+    Tempo(Tempo),
+    /// A bar line ending the measure before `line`.
+    Bar(Bar),
+    /// A jump, or a place to jump to, followed when the sheet is unrolled.
+    Navigation(Navigation),
+    /// A dynamic marking taking effect from `line`.
+    Dynamic(Dynamic),
+    /// A crescendo or diminuendo starting at `line`.
+    Hairpin(Hairpin),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    /// `|`
+    Single,
+    /// `|:`, where the next repeat starts over.
+    RepeatStart,
+    /// `:|`, or `:|x3` to play the repeated section three times in all.
+    RepeatEnd(u32),
+    /// `|1.`, starting the ending played on the given pass through a repeat.
+    Ending(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Navigation {
+    /// `@segno`, where D.S. jumps back to.
+    Segno,
+    /// `@tocoda`, where the replay after D.C. or D.S. al coda leaves for the coda.
+    ToCoda,
+    /// `@coda`, the start of the coda.
+    Coda,
+    /// `@fine`, where the replay after D.C. or D.S. al fine stops.
+    Fine,
+    /// `@dc`, `@dc al fine` or `@dc al coda`, going back to the beginning.
+    DaCapo(Until),
+    /// `@ds`, `@ds al fine` or `@ds al coda`, going back to the segno.
+    DalSegno(Until),
+}
+
+/// How loud the notes are played, from pianissimo to fortissimo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dynamic {
+    Pianissimo,
+    Piano,
+    MezzoPiano,
+    MezzoForte,
+    Forte,
+    Fortissimo,
+}
+
+impl Dynamic {
+    /// The velocity given to notes played at this dynamic, on the MIDI scale.
+    pub fn velocity(self) -> u8 {
+        match self {
+            Dynamic::Pianissimo => 33,
+            Dynamic::Piano => 49,
+            Dynamic::MezzoPiano => 64,
+            Dynamic::MezzoForte => 80,
+            Dynamic::Forte => 96,
+            Dynamic::Fortissimo => 112,
+        }
+    }
+}
+
+/// A gradual change between two dynamics over a number of lines. The notes under it keep the
+/// velocity of `from`; the change is applied as a gain on each note, taken where it starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hairpin {
+    pub from: Dynamic,
+    pub to: Dynamic,
+    pub lines: usize,
+    pub curve: Curve,
+}
+
+impl Hairpin {
+    /// Gain `fraction` of the way through the hairpin, relative to the velocity of `from`.
+    pub fn gain(&self, fraction: f32) -> f32 {
+        let to = self.to.velocity() as f32 / self.from.velocity() as f32;
+        self.curve.interpolate(1.0, to, fraction)
+    }
+}
+
+/// The shape of a gradual change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    /// Changes by the same ratio over equal times, which sounds even to the ear.
+    Exponential,
+}
+
+impl Curve {
+    /// The value `fraction` of the way from `from` to `to`. Exponential curves need both ends
+    /// above zero.
+    pub fn interpolate(self, from: f32, to: f32, fraction: f32) -> f32 {
+        match self {
+            Curve::Linear => from + (to - from) * fraction,
+            Curve::Exponential => from * (to / from).powf(fraction),
+        }
+    }
+}
+
+/// How far the replay after D.C. or D.S. goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Until {
+    End,
+    Fine,
+    Coda,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tempo {
+    /// An immediate change, written `@bpm 60`.
+    Set(Bpm),
+    /// A gradual change from one tempo to another over a number of lines, written
+    /// `@rit 80->50 over 8` or `@accel 50->80 over 8`. The lines after it keep the final tempo.
+    Ramp { from: Bpm, to: Bpm, lines: usize },
+}
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct Note {
     pub pitch: Pitch,
     pub value: Value,
     pub modifier: Modifier,
+    /// Number of augmentation dots, each adding half of the previous duration.
+    pub dots: u8,
+    /// Whether the note is tied into the next note of the same pitch.
+    pub tie: bool,
+    /// How hard the note is played, from 1 to [`MAX_VELOCITY`], or `None` for as hard as
+    /// possible when no dynamic applies.
+    pub velocity: Option<u8>,
+    /// How the note is played, which shapes how long it sounds and how hard its attack is, or
+    /// `None` for a plain note.
+    pub articulation: Option<Articulation>,
+}
+
+impl Note {
+    pub fn new(pitch: Pitch, value: Value, modifier: Modifier) -> Note {
+        Note {
+            pitch,
+            value,
+            modifier,
+            dots: 0,
+            tie: false,
+            velocity: None,
+            articulation: None,
+        }
+    }
+
+    /// Gives a note without a velocity of its own the velocity of `dynamic`.
+    pub fn at_dynamic(self, dynamic: Option<Dynamic>) -> Note {
+        Note {
+            velocity: self.velocity.or(dynamic.map(Dynamic::velocity)),
+            ..self
+        }
+    }
+
+    /// Peak amplitude of the note, between 0 and 1, following its velocity.
+    pub fn amplitude(&self) -> f32 {
+        self.velocity.unwrap_or(MAX_VELOCITY) as f32 / MAX_VELOCITY as f32
+    }
+
+    /// Resolves an unspecified modifier through the accidentals of `key`, or to natural when
+    /// there is no key. Explicit modifiers, including naturals, are left alone.
+    pub fn in_key(self, key: Option<Key>) -> Note {
+        let modifier = match (self.modifier, key) {
+            (Modifier::Unspecified, Some(key)) => key.accidental(self.pitch.letter()),
+            (Modifier::Unspecified, None) => Modifier::Natural,
+            (modifier, _) => modifier,
+        };
+        Note { modifier, ..self }
+    }
+
+    /// MIDI note number of the sounding pitch, with the accidental applied.
+    pub fn midi(&self) -> i32 {
+        self.pitch.midi() + self.modifier.semitones()
+    }
+
+    /// Equal-tempered frequency in Hz, relative to `reference_a4` for A4.
+    pub fn frequency(&self, reference_a4: f32) -> f32 {
+        reference_a4 * 2f32.powf((self.midi() - 69) as f32 / 12.0)
+    }
+
+    /// Fraction of its written length the note sounds for.
+    pub fn sounding(&self) -> f32 {
+        self.articulation.map_or(1.0, Articulation::sounding)
+    }
+
+    /// Duration of the note as a fraction of a whole note, including dots.
+    pub fn length(&self) -> f32 {
+        self.value.dotted(self.dots)
+    }
+}
+
+/// How a note is played, written as a suffix after its accidental.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Articulation {
+    /// `'`: short and detached.
+    Staccato,
+    /// `_`: carried over into the next note without a gap.
+    Legato,
+    /// `>`: with a stronger attack.
+    Accent,
+    /// `-`: held for its full length.
+    Tenuto,
+    /// `^`: strongly accented and slightly detached.
+    Marcato,
+}
+
+impl Articulation {
+    /// Fraction of its written length a note with this articulation sounds for. Legato notes
+    /// sound past their end, so that their release overlaps the next note.
+    pub fn sounding(self) -> f32 {
+        match self {
+            Articulation::Staccato => 0.5,
+            Articulation::Legato => 1.25,
+            Articulation::Marcato => 0.75,
+            Articulation::Accent | Articulation::Tenuto => 1.0,
+        }
+    }
+
+    /// How much louder than the rest of the note its attack peaks. This goes past full scale
+    /// for loud notes, up to 1.8 times for a marcato note without a velocity, the same way
+    /// chords add up past it in the mix; the output gain brings both back down.
+    pub fn attack(self) -> f32 {
+        match self {
+            Articulation::Accent => 1.5,
+            Articulation::Marcato => 1.8,
+            _ => 1.0,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
@@ -46,6 +566,8 @@ pub enum Value {
     Quarter,
     Eighth,
     Sixteenth,
+    ThirtySecond,
+    SixtyFourth,
 }
 
 impl Value {
@@ -56,15 +578,149 @@ impl Value {
             Value::Quarter => 0.25,
             Value::Eighth => 0.125,
             Value::Sixteenth => 0.0625,
+            Value::ThirtySecond => 0.03125,
+            Value::SixtyFourth => 0.015625,
         }
     }
+
+    /// The number of these notes in a whole note, as written under a time signature.
+    pub fn denominator(&self) -> u32 {
+        (1.0 / self.divisor()) as u32
+    }
+
+    /// Duration as a fraction of a whole note with `dots` augmentation dots, each adding half
+    /// of the previous duration.
+    pub fn dotted(&self, dots: u8) -> f32 {
+        self.divisor() * (2.0 - 0.5f32.powi(dots as i32))
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Modifier {
+    DoubleSharp,
     Sharp,
     Natural,
     Flat,
+    DoubleFlat,
+    /// No accidental was written, so the key signature decides. Sounds as natural if it is
+    /// never resolved.
+    Unspecified,
+}
+
+impl Modifier {
+    /// Semitones the modifier raises (or, when negative, lowers) a natural pitch by.
+    pub fn semitones(&self) -> i32 {
+        match &self {
+            Modifier::DoubleSharp => 2,
+            Modifier::Sharp => 1,
+            Modifier::Natural => 0,
+            Modifier::Flat => -1,
+            Modifier::DoubleFlat => -2,
+            Modifier::Unspecified => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub tonic: Letter,
+    pub modifier: Modifier,
+    pub mode: Mode,
+}
+
+impl Key {
+    /// Position of the key on the circle of fifths: the number of sharps when positive, or of
+    /// flats when negative. Keys beyond seven need double accidentals.
+    pub fn fifths(&self) -> i32 {
+        let letter = match self.tonic {
+            Letter::F => -1,
+            Letter::C => 0,
+            Letter::G => 1,
+            Letter::D => 2,
+            Letter::A => 3,
+            Letter::E => 4,
+            Letter::B => 5,
+        };
+        let mode = match self.mode {
+            Mode::Major => 0,
+            Mode::Minor => -3,
+        };
+        letter + 7 * self.modifier.semitones() + mode
+    }
+
+    /// The accidental the key signature gives to notes written with `letter`.
+    pub fn accidental(&self, letter: Letter) -> Modifier {
+        // Sharps are added in the order F C G D A E B and flats in the reverse order.
+        let order = [
+            Letter::F,
+            Letter::C,
+            Letter::G,
+            Letter::D,
+            Letter::A,
+            Letter::E,
+            Letter::B,
+        ];
+        let fifths = self.fifths();
+        let position = match fifths {
+            f if f >= 0 => order.iter().position(|l| *l == letter),
+            _ => order.iter().rev().position(|l| *l == letter),
+        }
+        .unwrap() as i32;
+        let count = (fifths.abs() - position + 6) / 7;
+
+        match (fifths >= 0, count) {
+            (_, 0) => Modifier::Natural,
+            (true, 1) => Modifier::Sharp,
+            (true, _) => Modifier::DoubleSharp,
+            (false, 1) => Modifier::Flat,
+            (false, _) => Modifier::DoubleFlat,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimeSignature {
+    pub beats: u32,
+    pub unit: Value,
+}
+
+impl TimeSignature {
+    /// Length of a full measure as a fraction of a whole note.
+    pub fn measure(&self) -> f32 {
+        self.beats as f32 * self.unit.divisor()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Ord, PartialOrd, PartialEq, Eq, Hash)]
+pub enum Letter {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+}
+
+impl Letter {
+    /// Semitones above C of the natural pitch with this letter.
+    pub fn semitones(&self) -> i32 {
+        match &self {
+            Letter::C => 0,
+            Letter::D => 2,
+            Letter::E => 4,
+            Letter::F => 5,
+            Letter::G => 7,
+            Letter::A => 9,
+            Letter::B => 11,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Ord, PartialOrd, PartialEq, Eq, Hash)]
@@ -129,3 +785,207 @@ pub enum Pitch {
     B7,
     C8,
 }
+
+impl Pitch {
+    pub fn letter(&self) -> Letter {
+        match *self as u8 % 7 {
+            0 => Letter::A,
+            1 => Letter::B,
+            2 => Letter::C,
+            3 => Letter::D,
+            4 => Letter::E,
+            5 => Letter::F,
+            _ => Letter::G,
+        }
+    }
+
+    /// Scientific octave number, which increments at C.
+    pub fn octave(&self) -> i32 {
+        (*self as i32 + 5) / 7
+    }
+
+    /// MIDI note number of the natural pitch, where C4 is 60 and A4 is 69.
+    pub fn midi(&self) -> i32 {
+        12 * (self.octave() + 1) + self.letter().semitones()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sheet::{
+        Curve, Dynamic, Event, Hairpin, Key, Letter, Line, Mark, MarkKind, Mode, Modifier, Note,
+        Pitch, Sheet, Tempo, Tuplet, Value,
+    };
+
+    #[test]
+    fn pitch_math() {
+        let note = |pitch, modifier| Note::new(pitch, Value::Quarter, modifier);
+        assert_eq!(Pitch::A0.midi(), 21);
+        assert_eq!(Pitch::C4.midi(), 60);
+        assert_eq!(Pitch::C8.midi(), 108);
+        assert_eq!(note(Pitch::A4, Modifier::Natural).frequency(440.0), 440.0);
+        assert_eq!(note(Pitch::A4, Modifier::Natural).frequency(415.0), 415.0);
+        assert!((note(Pitch::C4, Modifier::Natural).frequency(440.0) - 261.6256).abs() < 1e-3);
+        assert!((note(Pitch::C8, Modifier::Natural).frequency(440.0) - 4186.009).abs() < 1e-2);
+
+        // Enharmonic spellings that used to be missing from the frequency table.
+        assert_eq!(note(Pitch::B3, Modifier::Sharp).midi(), Pitch::C4.midi());
+        assert_eq!(note(Pitch::C4, Modifier::Flat).midi(), Pitch::B3.midi());
+        assert_eq!(note(Pitch::E4, Modifier::Sharp).midi(), Pitch::F4.midi());
+        assert_eq!(note(Pitch::F4, Modifier::Flat).midi(), Pitch::E4.midi());
+        assert_eq!(note(Pitch::A0, Modifier::Flat).midi(), 20);
+        assert_eq!(
+            note(Pitch::F4, Modifier::DoubleSharp).midi(),
+            Pitch::G4.midi()
+        );
+        assert_eq!(
+            note(Pitch::B3, Modifier::DoubleFlat).midi(),
+            Pitch::A3.midi()
+        );
+    }
+
+    #[test]
+    fn key_accidentals() {
+        let key = |tonic, modifier, mode| Key {
+            tonic,
+            modifier,
+            mode,
+        };
+        let d_major = key(Letter::D, Modifier::Natural, Mode::Major);
+        assert_eq!(d_major.fifths(), 2);
+        assert_eq!(d_major.accidental(Letter::F), Modifier::Sharp);
+        assert_eq!(d_major.accidental(Letter::C), Modifier::Sharp);
+        assert_eq!(d_major.accidental(Letter::G), Modifier::Natural);
+
+        let c_minor = key(Letter::C, Modifier::Natural, Mode::Minor);
+        assert_eq!(c_minor.fifths(), -3);
+        assert_eq!(c_minor.accidental(Letter::A), Modifier::Flat);
+        assert_eq!(c_minor.accidental(Letter::D), Modifier::Natural);
+
+        let g_sharp_major = key(Letter::G, Modifier::Sharp, Mode::Major);
+        assert_eq!(g_sharp_major.fifths(), 8);
+        assert_eq!(g_sharp_major.accidental(Letter::F), Modifier::DoubleSharp);
+        assert_eq!(g_sharp_major.accidental(Letter::C), Modifier::Sharp);
+
+        let note = Note::new(Pitch::F4, Value::Quarter, Modifier::Unspecified);
+        assert_eq!(note.in_key(Some(d_major)).modifier, Modifier::Sharp);
+        assert_eq!(note.in_key(None).modifier, Modifier::Natural);
+    }
+
+    #[test]
+    fn tied_events() {
+        let tied = Note {
+            tie: true,
+            ..Note::new(Pitch::D4, Value::Quarter, Modifier::Natural)
+        };
+        let held = Note::new(Pitch::D4, Value::Eighth, Modifier::Natural);
+        let other = Note::new(Pitch::A3, Value::Eighth, Modifier::Natural);
+        let sheet = Sheet::new(
+            60.0,
+            Value::Eighth,
+            vec![
+                Line::new(vec![tied, other]),
+                Line::new(vec![held]),
+                Line::new(vec![held]),
+                Line::new(vec![held]),
+            ],
+        );
+        let expected = vec![
+            Event {
+                line: 0,
+                offset: 0.0,
+                note: tied,
+                length: 0.375,
+            },
+            Event {
+                line: 0,
+                offset: 0.0,
+                note: other,
+                length: 0.125,
+            },
+            Event {
+                line: 1,
+                offset: 0.0,
+                note: held,
+                length: 0.125,
+            },
+            Event {
+                line: 3,
+                offset: 0.0,
+                note: held,
+                length: 0.125,
+            },
+        ];
+        assert_eq!(sheet.events(), expected);
+    }
+
+    #[test]
+    fn tempo_map() {
+        let mut sheet = Sheet::new(60.0, Value::Quarter, vec![Line::new(Vec::new()); 6]);
+        sheet.marks = vec![
+            Mark {
+                line: 1,
+                kind: MarkKind::Tempo(Tempo::Set(120.0)),
+            },
+            Mark {
+                line: 2,
+                kind: MarkKind::Tempo(Tempo::Ramp {
+                    from: 120.0,
+                    to: 60.0,
+                    lines: 2,
+                }),
+            },
+        ];
+        let map = sheet.tempo_map();
+        assert_eq!(map.time(0.0), 0.0);
+        assert_eq!(map.time(0.5), 0.125);
+        assert_eq!(map.time(1.0), 0.25);
+        assert_eq!(map.time(2.0), 0.375);
+        // The whole ritardando takes ln(2) / 2 seconds, slowing down from line to line.
+        assert!((map.time(4.0) - (0.375 + 15.0 * 2f64.ln() / 30.0)).abs() < 1e-5);
+        assert!(map.time(3.0) - map.time(2.0) < map.time(4.0) - map.time(3.0));
+        assert!((map.time(6.0) - map.time(4.0) - 0.5).abs() < 1e-5);
+        assert!((map.time(7.0) - map.time(6.0) - 0.25).abs() < 1e-5);
+        assert_eq!(map.sample(1.0, 48000), 12000);
+    }
+
+    #[test]
+    fn tempo_map_without_drift() {
+        let sheet = Sheet::new(37.3, Value::Sixteenth, vec![Line::new(Vec::new()); 100_000]);
+        let map = sheet.tempo_map();
+        let line_time = 60.0 / 16.0 / 37.3;
+        for line in [1, 999, 100_000] {
+            let expected = (line as f64 * line_time * 44100.0).round() as usize;
+            assert_eq!(map.sample(line as f64, 44100), expected);
+        }
+    }
+
+    #[test]
+    fn hairpin_gain() {
+        let hairpin = |curve| Hairpin {
+            from: Dynamic::Piano,
+            to: Dynamic::Forte,
+            lines: 4,
+            curve,
+        };
+        let linear = hairpin(Curve::Linear);
+        let exponential = hairpin(Curve::Exponential);
+        let end = 96.0 / 49.0;
+        for hairpin in [linear, exponential] {
+            assert_eq!(hairpin.gain(0.0), 1.0);
+            assert!((hairpin.gain(1.0) - end).abs() < 1e-6);
+        }
+        assert!((linear.gain(0.5) - (1.0 + end) / 2.0).abs() < 1e-6);
+        assert!((exponential.gain(0.5) - end.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tuplet_normal() {
+        let normal = |actual| Tuplet::new(actual, Vec::new()).normal;
+        assert_eq!(normal(2), 3);
+        assert_eq!(normal(3), 2);
+        assert_eq!(normal(128), 64);
+        assert_eq!(normal(129), 128);
+        assert_eq!(normal(255), 128);
+    }
+}
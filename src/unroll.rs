@@ -0,0 +1,298 @@
+use nom::lib::std::collections::{HashMap, HashSet};
+
+use crate::sheet::{Bar, Line, Mark, MarkKind, Navigation, Sheet, Track, Until};
+
+/// A step through the sheet in written order: each line is preceded by the marks attached to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Item<'a> {
+    Mark(&'a MarkKind),
+    Line(usize),
+}
+
+/// Expands repeats, endings and D.C./D.S. jumps into the lines as they are played.
+///
+/// A `:|` goes back to the last `|:`, or to the start of the sheet or the end of the previous
+/// repeat when there is none. An ending closed by `:|` is played from the pass with its number
+/// up to the pass of the ending after it; the last ending is left open, runs to the next bar
+/// line and is played on its pass or any later one. After D.C. or D.S. the repeats are not taken
+/// again and only the last ending is played. Every jump is taken once.
+///
+/// The unrolled sheet keeps the marks of the lines it plays, without the repeat and
+/// navigation marks it has followed. Its tracks are unrolled along with the lines. Hairpins span
+/// the lines played up to the next dynamic marking, wherever the jumps take them; a hairpin cut
+/// short by the end of the piece keeps its written span.
+pub fn unroll(sheet: &Sheet) -> Sheet {
+    let mut items = Vec::with_capacity(sheet.lines.len() + sheet.marks.len());
+    let mut marks = sheet.marks.iter().peekable();
+    for line in 0..=sheet.lines.len() {
+        while let Some(mark) = marks.next_if(|mark| mark.line <= line) {
+            items.push(Item::Mark(&mark.kind));
+        }
+        if line < sheet.lines.len() {
+            items.push(Item::Line(line));
+        }
+    }
+    let find = |navigation| {
+        items
+            .iter()
+            .position(|item| *item == Item::Mark(&MarkKind::Navigation(navigation)))
+    };
+    let segno = find(Navigation::Segno).unwrap_or(0);
+    let coda = find(Navigation::Coda);
+
+    let mut out = Sheet {
+        lines: Vec::new(),
+        marks: Vec::new(),
+        tracks: sheet
+            .tracks
+            .iter()
+            .map(|track| Track {
+                name: track.name.clone(),
+                lines: Vec::new(),
+            })
+            .collect(),
+        ..sheet.clone()
+    };
+    let mut cursor = 0;
+    let mut repeat_start = 0;
+    let mut pass = 1;
+    let mut repeats: HashMap<usize, u32> = HashMap::new();
+    let mut jumped = HashSet::new();
+    let mut replay: Option<Until> = None;
+    // The mark of the hairpin played last, until the dynamic marking that ends it.
+    let mut hairpin: Option<usize> = None;
+
+    while cursor < items.len() {
+        let kind = match items[cursor] {
+            Item::Line(line) => {
+                out.lines.push(sheet.lines[line].clone());
+                // Tracks follow the lines they are played with.
+                for (track, played) in sheet.tracks.iter().zip(out.tracks.iter_mut()) {
+                    let line = track.lines.get(line).cloned();
+                    played
+                        .lines
+                        .push(line.unwrap_or_else(|| Line::new(Vec::new())));
+                }
+                cursor += 1;
+                continue;
+            }
+            Item::Mark(kind) => kind,
+        };
+        match kind {
+            MarkKind::Bar(Bar::RepeatStart) => {
+                repeat_start = cursor + 1;
+                pass = 1;
+            }
+            MarkKind::Bar(Bar::RepeatEnd(times)) => {
+                let taken = repeats.entry(cursor).or_insert(0);
+                if replay.is_none() && *taken + 1 < *times {
+                    *taken += 1;
+                    pass += 1;
+                    cursor = repeat_start;
+                    continue;
+                }
+                repeats.remove(&cursor);
+                repeat_start = cursor + 1;
+                if !matches!(
+                    items.get(cursor + 1),
+                    Some(Item::Mark(MarkKind::Bar(Bar::Ending(_))))
+                ) {
+                    pass = 1;
+                }
+            }
+            MarkKind::Bar(Bar::Ending(ending)) => {
+                let (close, next) = ending_close(&items, cursor);
+                let plays = match (close, replay) {
+                    (Some(_), Some(_)) => false,
+                    (None, Some(_)) => true,
+                    (Some(_), None) => pass >= *ending && next.map_or(true, |next| pass < next),
+                    (None, None) => pass >= *ending,
+                };
+                if !plays {
+                    cursor = match close {
+                        Some(close) => {
+                            repeats.remove(&close);
+                            close + 1
+                        }
+                        None => next_bar(&items, cursor),
+                    };
+                    continue;
+                }
+                if close.is_none() {
+                    pass = 1;
+                }
+            }
+            MarkKind::Navigation(Navigation::DaCapo(until))
+            | MarkKind::Navigation(Navigation::DalSegno(until)) => {
+                if jumped.insert(cursor) {
+                    replay = Some(*until);
+                    repeat_start = 0;
+                    pass = 1;
+                    cursor = match kind {
+                        MarkKind::Navigation(Navigation::DaCapo(_)) => 0,
+                        _ => segno,
+                    };
+                    continue;
+                }
+            }
+            MarkKind::Navigation(Navigation::Fine) if replay == Some(Until::Fine) => break,
+            MarkKind::Navigation(Navigation::ToCoda) if replay == Some(Until::Coda) => {
+                if let Some(coda) = coda {
+                    replay = None;
+                    cursor = coda + 1;
+                    continue;
+                }
+            }
+            MarkKind::Navigation(_) => {}
+            _ => {
+                if let (MarkKind::Dynamic(_), Some(index)) = (kind, hairpin.take()) {
+                    let mark = &mut out.marks[index];
+                    if let MarkKind::Hairpin(hairpin) = &mut mark.kind {
+                        hairpin.lines = out.lines.len() - mark.line;
+                    }
+                }
+                if let MarkKind::Hairpin(_) = kind {
+                    hairpin = Some(out.marks.len());
+                }
+                out.marks.push(Mark {
+                    line: out.lines.len(),
+                    kind: kind.clone(),
+                });
+            }
+        }
+        cursor += 1;
+    }
+
+    out
+}
+
+/// Index of the `:|` closing the ending at `start`, or `None` for the last, open ending, along
+/// with the number of the ending right after that `:|`.
+fn ending_close(items: &[Item], start: usize) -> (Option<usize>, Option<u32>) {
+    for (index, item) in items.iter().enumerate().skip(start + 1) {
+        match item {
+            Item::Mark(MarkKind::Bar(Bar::RepeatEnd(_))) => {
+                let next = match items.get(index + 1) {
+                    Some(Item::Mark(MarkKind::Bar(Bar::Ending(next)))) => Some(*next),
+                    _ => None,
+                };
+                return (Some(index), next);
+            }
+            Item::Mark(MarkKind::Bar(_)) => return (None, None),
+            _ => {}
+        }
+    }
+    (None, None)
+}
+
+/// Index of the first bar line after `start`, or the end of the sheet.
+fn next_bar(items: &[Item], start: usize) -> usize {
+    items
+        .iter()
+        .skip(start + 1)
+        .position(|item| matches!(item, Item::Mark(MarkKind::Bar(_))))
+        .map_or(items.len(), |offset| start + 1 + offset)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::parse::sheet;
+    use crate::sheet::{MarkKind, Pitch};
+    use crate::unroll::unroll;
+
+    /// The first note of every unrolled line, as its pitch.
+    fn played(input: &str) -> Vec<Pitch> {
+        unroll(&sheet(Path::new("test.sht"), input).unwrap())
+            .lines
+            .iter()
+            .map(|line| line.notes[0].pitch)
+            .collect()
+    }
+
+    #[test]
+    fn repeats() {
+        use Pitch::*;
+        assert_eq!(
+            played("90xq\n--\nC4q\n|:\nD4q\n:|\nE4q"),
+            vec![C4, D4, D4, E4]
+        );
+        assert_eq!(
+            played("90xq\n--\nC4q\n|\nD4q\n:|x3\nE4q"),
+            vec![C4, D4, C4, D4, C4, D4, E4]
+        );
+        assert_eq!(
+            played("90xq\n--\n|:\nC4q\n|1.\nD4q\n:|\n|2.\nE4q\n|\nF4q"),
+            vec![C4, D4, C4, E4, F4]
+        );
+        assert_eq!(
+            played("90xq\n--\n|:\nC4q\n|1.\nD4q\n:|x3\n|3.\nE4q\n|\nF4q"),
+            vec![C4, D4, C4, D4, C4, E4, F4]
+        );
+        assert_eq!(
+            played("90xq\n--\n|:\nC4q\n:|:\nD4q\n:|\nE4q"),
+            vec![C4, C4, D4, D4, E4]
+        );
+    }
+
+    #[test]
+    fn navigation() {
+        use Pitch::*;
+        assert_eq!(
+            played("90xq\n--\n|:\nC4q\n:|\nD4q\n@fine\nE4q\n@dc al fine"),
+            vec![C4, C4, D4, E4, C4, D4]
+        );
+        assert_eq!(
+            played("90xq\n--\nC4q\n@segno\nD4q\n@tocoda\nE4q\n@ds al coda\n@coda\nF4q"),
+            vec![C4, D4, E4, D4, F4]
+        );
+        assert_eq!(
+            played("90xq\n--\n|:\nC4q\n|1.\nD4q\n:|\n|2.\nE4q\n|\n@dc"),
+            vec![C4, D4, C4, E4, C4, E4]
+        );
+    }
+
+    #[test]
+    fn tracks() {
+        use Pitch::*;
+        let input = "90xq\n--\n|:\nC4q\n:|\n\n[track bass]\nC3q D3q E3q";
+        let unrolled = unroll(&sheet(Path::new("test.sht"), input).unwrap());
+        let bass = unrolled.tracks[0]
+            .lines
+            .iter()
+            .map(|line| line.notes[0].pitch)
+            .collect::<Vec<_>>();
+        assert_eq!(bass, vec![C3, C3, D3, E3]);
+    }
+
+    #[test]
+    fn hairpins() {
+        let input = "90xq\n--\n@p\n@cresc\nC4q\n@fine\nD4q\n@f\nE4q\n@dc al fine";
+        let unrolled = unroll(&sheet(Path::new("test.sht"), input).unwrap());
+        let hairpins = unrolled
+            .marks
+            .iter()
+            .filter_map(|mark| match mark.kind {
+                MarkKind::Hairpin(hairpin) => Some((mark.line, hairpin.lines)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // The replay stops at the fine, part of the way through the second crescendo.
+        assert_eq!(hairpins, vec![(0, 2), (3, 2)]);
+        assert_eq!(unrolled.lines.len(), 4);
+
+        let input = "90xq\n--\n|:\n@p\nC4q\n@cresc\nD4q\n:|\n@f\nE4q";
+        let unrolled = unroll(&sheet(Path::new("test.sht"), input).unwrap());
+        let hairpins = unrolled
+            .marks
+            .iter()
+            .filter_map(|mark| match mark.kind {
+                MarkKind::Hairpin(hairpin) => Some((mark.line, hairpin.lines)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // The first crescendo ends where the repeat goes back to the piano.
+        assert_eq!(hairpins, vec![(1, 1), (3, 1)]);
+    }
+}